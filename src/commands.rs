@@ -1,46 +1,200 @@
+use std::fmt::{Debug, Formatter};
+use std::rc::Rc;
+
+use crate::context::ExecutionContext;
+use crate::implementation::Implementation;
+use crate::tokens::{FinlError, Location, Token};
+
+// Called with the parsed arguments of a command. The returned tokens replace the command in the output.
+pub trait CommandHandler {
+    fn execute(&self, context: &mut ExecutionContext, location: &Location, args: Vec<Token>) -> Result<Vec<Token>, FinlError>;
+}
+
+impl<F> CommandHandler for F
+    where F: Fn(&mut ExecutionContext, &Location, Vec<Token>) -> Result<Vec<Token>, FinlError> {
+    fn execute(&self, context: &mut ExecutionContext, location: &Location, args: Vec<Token>) -> Result<Vec<Token>, FinlError> {
+        self(context, location, args)
+    }
+}
+
+// As CommandHandler, but also given the environment's body
+pub trait EnvironmentHandler {
+    fn execute(&self, context: &mut ExecutionContext, location: &Location, args: Vec<Token>, body: Vec<Token>) -> Result<Vec<Token>, FinlError>;
+}
+
+impl<F> EnvironmentHandler for F
+    where F: Fn(&mut ExecutionContext, &Location, Vec<Token>, Vec<Token>) -> Result<Vec<Token>, FinlError> {
+    fn execute(&self, context: &mut ExecutionContext, location: &Location, args: Vec<Token>, body: Vec<Token>) -> Result<Vec<Token>, FinlError> {
+        self(context, location, args, body)
+    }
+}
 
-#[derive(Debug, PartialEq)]
 pub struct Command {
     pub name: String,
     pub parameters: Vec<(ParameterFormat, ParameterType)>,
-    //TODO: Function pointer for execution
+    pub handler: Option<Rc<dyn CommandHandler>>,
+    pub implementation: Option<Implementation>,
+    pub body: Option<Vec<Token>>, // for macros
 }
 
 impl Command {
     pub fn new(name: &str, args: Vec<(ParameterFormat, ParameterType)>) -> Command {
         Command {
             name: name.to_string(),
-            parameters: args
+            parameters: args,
+            handler: None,
+            implementation: None,
+            body: None
+        }
+    }
+
+    pub fn with_handler(name: &str, args: Vec<(ParameterFormat, ParameterType)>, handler: Rc<dyn CommandHandler>) -> Command {
+        Command {
+            name: name.to_string(),
+            parameters: args,
+            handler: Some(handler),
+            implementation: None,
+            body: None
         }
     }
-    
+
+    pub fn with_implementation(name: &str, args: Vec<(ParameterFormat, ParameterType)>, implementation: Implementation) -> Command {
+        Command {
+            name: name.to_string(),
+            parameters: args,
+            handler: None,
+            implementation: Some(implementation),
+            body: None
+        }
+    }
+
+    pub fn with_body(name: &str, args: Vec<(ParameterFormat, ParameterType)>, body: Vec<Token>) -> Command {
+        Command {
+            name: name.to_string(),
+            parameters: args,
+            handler: None,
+            implementation: None,
+            body: Some(body)
+        }
+    }
+
     pub fn no_arg_command(name: String) -> Command {
         Command {
             name,
-            parameters: Vec::default()
+            parameters: Vec::default(),
+            handler: None,
+            implementation: None,
+            body: None
         }
     }
-    
-    
 }
 
-#[derive(Debug, PartialEq)]
+// Handlers can't be compared or printed, so we go by identity
+impl PartialEq for Command {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.parameters == other.parameters && same_handler(&self.handler, &other.handler)
+            && self.implementation == other.implementation && self.body == other.body
+    }
+}
+
+impl Debug for Command {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Command")
+            .field("name", &self.name)
+            .field("parameters", &self.parameters)
+            .field("handler", &self.handler.is_some())
+            .field("implementation", &self.implementation)
+            .field("body", &self.body)
+            .finish()
+    }
+}
+
 pub struct Environment {
     pub name: String,
     pub args: Vec<(ParameterFormat, ParameterType)>,
-    pub body_type: ParameterType
-    // TODO: Function pointer for execution
+    pub body_type: ParameterType,
+    pub handler: Option<Rc<dyn EnvironmentHandler>>,
+}
+
+impl Environment {
+    pub fn new(name: &str, args: Vec<(ParameterFormat, ParameterType)>, body_type: ParameterType) -> Environment {
+        Environment {
+            name: name.to_string(),
+            args,
+            body_type,
+            handler: None
+        }
+    }
+
+    pub fn with_handler(name: &str, args: Vec<(ParameterFormat, ParameterType)>, body_type: ParameterType, handler: Rc<dyn EnvironmentHandler>) -> Environment {
+        Environment {
+            name: name.to_string(),
+            args,
+            body_type,
+            handler: Some(handler)
+        }
+    }
+}
+
+impl PartialEq for Environment {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.args == other.args && self.body_type == other.body_type
+            && same_handler(&self.handler, &other.handler)
+    }
+}
+
+impl Debug for Environment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Environment")
+            .field("name", &self.name)
+            .field("args", &self.args)
+            .field("body_type", &self.body_type)
+            .field("handler", &self.handler.is_some())
+            .finish()
+    }
 }
 
-#[derive(Debug, PartialEq)]
+fn same_handler<T: ?Sized>(a: &Option<Rc<T>>, b: &Option<Rc<T>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+        (None, None) => true,
+        _ => false
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum ParameterFormat {
     Star,
     Required,
     RequiredWithBraces,
     Optional,
+    OptionalWithDefault(String), // the default is parsed wherever the argument is omitted
     ArbitraryDelimiters,
 }
 
+impl ParameterFormat {
+    // The names used in definition files. A default for an optional argument is given separately.
+    pub fn from_name(name: &str) -> Option<ParameterFormat> {
+        match name {
+            "star" => Some(ParameterFormat::Star),
+            "required" => Some(ParameterFormat::Required),
+            "required-with-braces" => Some(ParameterFormat::RequiredWithBraces),
+            "optional" => Some(ParameterFormat::Optional),
+            "arbitrary-delimiters" => Some(ParameterFormat::ArbitraryDelimiters),
+            _ => None
+        }
+    }
+
+    // The type to use when a definition file doesn't give one
+    pub fn default_type(&self) -> ParameterType {
+        match self {
+            ParameterFormat::Star => ParameterType::Boolean,
+            ParameterFormat::ArbitraryDelimiters => ParameterType::VerbatimText,
+            _ => ParameterType::ParsedTokens
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum ParameterType {
     ParsedTokens,
@@ -50,4 +204,19 @@ pub enum ParameterType {
     MacroDefinition,
     Math,
     YAML,
-}
\ No newline at end of file
+}
+
+impl ParameterType {
+    pub fn from_name(name: &str) -> Option<ParameterType> {
+        match name {
+            "parsed" => Some(ParameterType::ParsedTokens),
+            "verbatim" => Some(ParameterType::VerbatimText),
+            "boolean" => Some(ParameterType::Boolean),
+            "key-value" => Some(ParameterType::KeyValueList),
+            "macro" => Some(ParameterType::MacroDefinition),
+            "math" => Some(ParameterType::Math),
+            "yaml" => Some(ParameterType::YAML),
+            _ => None
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+use crate::variables::VariableStore;
+
+// State shared by command and environment handlers over the course of a parse. Handlers keep their own state
+// here, one value per type.
+#[derive(Default)]
+pub struct ExecutionContext {
+    pub variables: VariableStore,
+    state: HashMap<TypeId, Box<dyn Any>>,
+}
+
+impl ExecutionContext {
+    pub fn get<T: Any>(&self) -> Option<&T> {
+        self.state.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref())
+    }
+
+    pub fn get_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.state.get_mut(&TypeId::of::<T>()).and_then(|value| value.downcast_mut())
+    }
+
+    pub fn get_or_default<T: Any + Default>(&mut self) -> &mut T {
+        self.state.entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(T::default()))
+            .downcast_mut()
+            .expect("state is stored under its own type")
+    }
+
+    pub fn insert<T: Any>(&mut self, value: T) {
+        self.state.insert(TypeId::of::<T>(), Box::new(value));
+    }
+}
@@ -0,0 +1,331 @@
+use yaml_rust2::parser::{Event, MarkedEventReceiver, Parser as YamlParser};
+use yaml_rust2::scanner::{Marker, ScanError};
+
+use std::rc::Rc;
+
+use crate::Parser;
+use crate::commands::{Command, ParameterFormat, ParameterType};
+use crate::implementation::Implementation;
+use crate::tokens::{ErrorContext, FinlError, Location};
+
+// A YAML document where every node remembers where it came from so we can report errors against the file
+enum Node {
+    Scalar(String),
+    Sequence(Vec<MarkedNode>),
+    Mapping(Vec<(MarkedNode, MarkedNode)>),
+    Alias, // we don't support anchors, so an alias never has a value
+}
+
+struct MarkedNode {
+    node: Node,
+    marker: Marker,
+}
+
+#[derive(Default)]
+struct NodeBuilder {
+    // Containers we're inside of. Mappings hold on to a key until its value arrives.
+    stack: Vec<(Node, Marker, Option<MarkedNode>)>,
+    document: Option<MarkedNode>,
+}
+
+impl NodeBuilder {
+    fn add(&mut self, node: MarkedNode) {
+        match self.stack.last_mut() {
+            None => {
+                if self.document.is_none() {
+                    self.document = Some(node);
+                }
+            }
+            Some((Node::Sequence(items), _, _)) => items.push(node),
+            Some((Node::Mapping(entries), _, pending_key)) => {
+                match pending_key.take() {
+                    None => *pending_key = Some(node),
+                    Some(key) => entries.push((key, node)),
+                }
+            }
+            Some((Node::Scalar(_) | Node::Alias, _, _)) => unreachable!("scalars and aliases are never on the stack"),
+        }
+    }
+}
+
+impl MarkedEventReceiver for NodeBuilder {
+    fn on_event(&mut self, event: Event, marker: Marker) {
+        match event {
+            Event::Scalar(value, _, _, _) => self.add(MarkedNode { node: Node::Scalar(value), marker }),
+            Event::Alias(_) => self.add(MarkedNode { node: Node::Alias, marker }),
+            Event::SequenceStart(..) => self.stack.push((Node::Sequence(Vec::new()), marker, None)),
+            Event::MappingStart(..) => self.stack.push((Node::Mapping(Vec::new()), marker, None)),
+            Event::SequenceEnd | Event::MappingEnd => {
+                if let Some((node, marker, _)) = self.stack.pop() {
+                    self.add(MarkedNode { node, marker });
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// The byte offset in `line` of a YAML marker, whose column counts chars from 0
+pub(crate) fn marker_column(marker: &Marker, line: &str) -> usize {
+    line.char_indices().nth(marker.col()).map_or(line.len(), |(column, _)| column)
+}
+
+// The message of a YAML error without its position, which we report ourselves
+pub(crate) fn yaml_error_message(error: &ScanError) -> String {
+    let message = error.to_string();
+    message.rsplit_once(" at line ").map_or(message.as_str(), |(info, _)| info).to_string()
+}
+
+struct DefinitionLoader<'s> {
+    file: &'s str,
+    source: &'s str,
+    errors: Vec<FinlError>,
+}
+
+impl<'s> DefinitionLoader<'s> {
+    // The marker's line starts at 1
+    fn error_context(&self, marker: &Marker) -> ErrorContext {
+        let line_contents = self.source.lines().nth(marker.line().saturating_sub(1)).unwrap_or_default();
+        let column = marker_column(marker, line_contents);
+        ErrorContext {
+            location: Location {
+                file: self.file.to_string(),
+                line_number: marker.line(),
+                column,
+            },
+            line_contents: line_contents.to_string(),
+            expanded_from: Vec::new(),
+        }
+    }
+
+    fn invalid(&mut self, marker: &Marker, expected: &str) {
+        self.errors.push(FinlError::InvalidDefinition(self.error_context(marker), expected.to_string()));
+    }
+
+    // A node isn't what was expected. Since an alias is never what was expected, we say that it isn't supported.
+    fn unexpected(&mut self, node: &MarkedNode, expected: &str) {
+        match node.node {
+            Node::Alias => self.invalid(&node.marker, &format!("{} (aliases aren't supported)", expected)),
+            _ => self.invalid(&node.marker, expected),
+        }
+    }
+
+    fn scalar<'n>(&mut self, node: &'n MarkedNode, expected: &str) -> Option<&'n str> {
+        match &node.node {
+            Node::Scalar(value) => Some(value),
+            _ => {
+                self.unexpected(node, expected);
+                None
+            }
+        }
+    }
+
+    fn sequence<'n>(&mut self, node: &'n MarkedNode, expected: &str) -> Option<&'n [MarkedNode]> {
+        match &node.node {
+            Node::Sequence(items) => Some(items),
+            _ => {
+                self.unexpected(node, expected);
+                None
+            }
+        }
+    }
+
+    // Look up the values of `keys` in a mapping, complaining about any other key.
+    fn mapping<'n, const N: usize>(&mut self, node: &'n MarkedNode, keys: [&str; N], expected: &str) -> Option<[Option<&'n MarkedNode>; N]> {
+        let entries = match &node.node {
+            Node::Mapping(entries) => entries,
+            _ => {
+                self.unexpected(node, expected);
+                return None;
+            }
+        };
+        let mut values = [None; N];
+        for (key, value) in entries {
+            let key_name = self.scalar(key, "a key")?;
+            match keys.iter().position(|name| *name == key_name) {
+                Some(index) => values[index] = Some(value),
+                None => self.invalid(&key.marker, &format!("one of {}", keys.join(", "))),
+            }
+        }
+        Some(values)
+    }
+
+    fn definitions(&mut self, document: &MarkedNode) -> Definitions {
+        let mut definitions = Definitions::default();
+        let [command_list, environment_list, variables] =
+            match self.mapping(document, ["commands", "environments", "variables"],
+                               "a mapping of commands, environments and variables") {
+                Some(values) => values,
+                None => return definitions,
+            };
+        if let Some(command_list) = command_list.and_then(|list| self.sequence(list, "a list of commands")) {
+            definitions.commands = command_list.iter().filter_map(|command| self.command(command)).collect();
+        }
+        if let Some(environment_list) = environment_list.and_then(|list| self.sequence(list, "a list of environments")) {
+            definitions.environments = environment_list.iter().filter_map(|environment| self.environment(environment)).collect();
+        }
+        if let Some(variables) = variables {
+            self.variables(variables, "", &mut definitions.variables);
+        }
+        definitions
+    }
+
+    // Nested mappings give dotted paths, e.g., `font: { series: { bold: b } }` sets `font.series.bold`
+    fn variables(&mut self, node: &MarkedNode, prefix: &str, variables: &mut Vec<(String, String)>) {
+        match &node.node {
+            Node::Scalar(value) => variables.push((prefix.to_string(), value.clone())),
+            Node::Mapping(entries) => {
+                for (key, value) in entries {
+                    if let Some(key) = self.scalar(key, "a variable name") {
+                        let path = if prefix.is_empty() { key.to_string() } else { format!("{}.{}", prefix, key) };
+                        self.variables(value, &path, variables);
+                    }
+                }
+            }
+            Node::Sequence(_) | Node::Alias => self.unexpected(node, "a variable value or a mapping of variables"),
+        }
+    }
+
+    fn command(&mut self, node: &MarkedNode) -> Option<Command> {
+        let [name, params, implementation] =
+            self.mapping(node, ["command", "params", "implementation"], "a command definition")?;
+        let name = self.required_scalar(name, node, "a command name")?;
+        let parameters = self.parameters(params)?;
+        match implementation {
+            Some(implementation) => {
+                let implementation = self.implementation(implementation)?;
+                Some(Command::with_implementation(name, parameters, implementation))
+            }
+            None => Some(Command::new(name, parameters)),
+        }
+    }
+
+    fn implementation(&mut self, node: &MarkedNode) -> Option<Implementation> {
+        let [method, arguments] = self.mapping(node, ["method", "arguments"], "an implementation")?;
+        let method = self.required_scalar(method, node, "an implementation method")?;
+        let arguments = match arguments {
+            Some(arguments) => {
+                let arguments: Vec<_> = self.sequence(arguments, "a list of method arguments")?
+                    .iter()
+                    .map(|argument| self.scalar(argument, "a method argument").map(str::to_string))
+                    .collect();
+                arguments.into_iter().collect::<Option<Vec<String>>>()?
+            }
+            None => Vec::new(),
+        };
+        Some(Implementation::new(method, arguments))
+    }
+
+    fn environment(&mut self, node: &MarkedNode) -> Option<EnvironmentDefinition> {
+        let [name, params, body] =
+            self.mapping(node, ["environment", "params", "body"], "an environment definition")?;
+        let name = self.required_scalar(name, node, "an environment name")?;
+        let parameters = self.parameters(params)?;
+        let body_type = match body {
+            Some(body) => self.parameter_type(body)?,
+            None => ParameterType::ParsedTokens,
+        };
+        Some(EnvironmentDefinition { name: name.to_string(), parameters, body_type })
+    }
+
+    fn required_scalar<'n>(&mut self, value: Option<&'n MarkedNode>, parent: &MarkedNode, expected: &str) -> Option<&'n str> {
+        match value {
+            Some(value) => self.scalar(value, expected),
+            None => {
+                self.invalid(&parent.marker, expected);
+                None
+            }
+        }
+    }
+
+    // All parameters are checked so that every bad one gets reported
+    fn parameters(&mut self, node: Option<&MarkedNode>) -> Option<Vec<(ParameterFormat, ParameterType)>> {
+        let node = match node {
+            Some(node) => node,
+            None => return Some(Vec::new()),
+        };
+        let parameters: Vec<_> = self.sequence(node, "a list of parameters")?
+            .iter()
+            .map(|parameter| self.parameter(parameter))
+            .collect();
+        parameters.into_iter().collect()
+    }
+
+    fn parameter(&mut self, node: &MarkedNode) -> Option<(ParameterFormat, ParameterType)> {
+        let [format, ptype, default] = self.mapping(node, ["format", "type", "default"], "a parameter")?;
+        let format_node = format;
+        let format_name = self.required_scalar(format_node, node, "a parameter format")?;
+        let mut format = match ParameterFormat::from_name(format_name) {
+            Some(format) => format,
+            None => {
+                let context = self.error_context(&format_node.unwrap().marker);
+                self.errors.push(FinlError::UnknownParameterFormat(context, format_name.to_string()));
+                return None;
+            }
+        };
+        let ptype = match ptype {
+            Some(ptype) => self.parameter_type(ptype)?,
+            None => format.default_type(),
+        };
+        if let Some(default) = default {
+            if format != ParameterFormat::Optional {
+                self.invalid(&default.marker, "a default only for an optional parameter");
+                return None;
+            }
+            format = ParameterFormat::OptionalWithDefault(self.scalar(default, "a default value")?.to_string());
+        }
+        Some((format, ptype))
+    }
+
+    fn parameter_type(&mut self, node: &MarkedNode) -> Option<ParameterType> {
+        let name = self.scalar(node, "a parameter type")?;
+        let ptype = ParameterType::from_name(name);
+        if ptype.is_none() {
+            self.errors.push(FinlError::UnknownParameterType(self.error_context(&node.marker), name.to_string()));
+        }
+        ptype
+    }
+}
+
+#[derive(Default)]
+struct Definitions {
+    commands: Vec<Command>,
+    environments: Vec<EnvironmentDefinition>,
+    variables: Vec<(String, String)>,
+}
+
+struct EnvironmentDefinition {
+    name: String,
+    parameters: Vec<(ParameterFormat, ParameterType)>,
+    body_type: ParameterType,
+}
+
+impl<'a> Parser<'a> {
+    // Define the commands, environments and variables described by a YAML definition file like `resources/commands.yml`.
+    // `file` is only used for error locations. Definitions without errors are registered even if others fail.
+    pub fn define_from_yaml(&mut self, file: &str, source: &str) -> Result<(), Vec<FinlError>> {
+        let mut loader = DefinitionLoader { file, source, errors: Vec::new() };
+        let mut builder = NodeBuilder::default();
+        if let Err(error) = YamlParser::new(source.chars()).load(&mut builder, false) {
+            return Err(vec![FinlError::InvalidYAML(loader.error_context(error.marker()), yaml_error_message(&error))]);
+        }
+        if let Some(document) = builder.document {
+            let definitions = loader.definitions(&document);
+            for command in definitions.commands {
+                self.commands.insert(command.name.clone(), Rc::new(command));
+            }
+            for environment in definitions.environments {
+                self.define_environment(&environment.name, environment.parameters, environment.body_type);
+            }
+            for (path, value) in definitions.variables {
+                self.context.variables.set(&path, &value);
+            }
+        }
+        if loader.errors.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(loader.errors)
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use std::fmt::{Display, Formatter};
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::tokens::{FinlError, GroupType, Location};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    pub location: Location,
+    pub length: usize, // in graphemes
+    pub label: Option<String>,
+}
+
+// Everything there is to say about an error, for tools that want more than its message
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub code: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    pub primary: Span,
+    pub secondary: Vec<Span>,
+    pub help: Vec<String>,
+}
+
+impl FinlError {
+    // Codes never change once given out, so they can be used to filter or suppress errors. New variants get new
+    // codes at the end.
+    pub fn code(&self) -> &'static str {
+        match self {
+            FinlError::UndefinedCommand(..) => "F0001",
+            FinlError::Unimplemented(..) => "F0002",
+            FinlError::BlankLineWhileParsingCommandArguments(..) => "F0003",
+            FinlError::UnexpectedEOFWhileParsingCommandArguments(..) => "F0004",
+            FinlError::CloseBraceWhileParsingCommandArguments(..) => "F0005",
+            FinlError::MissingBracesForCommandArgument(..) => "F0006",
+            FinlError::UnexpectedEOLWhileParsingCommandArguments(..) => "F0007",
+            FinlError::UnexpectedCloseBrace(..) => "F0008",
+            FinlError::UndefinedEnvironment(..) => "F0009",
+            FinlError::MismatchedEnvironmentEnd(..) => "F0010",
+            FinlError::UnexpectedEnvironmentEnd(..) => "F0011",
+            FinlError::InsufficientIndentationInEnvironment(..) => "F0012",
+            FinlError::InvalidYAML(..) => "F0013",
+            FinlError::InvalidKey(..) => "F0014",
+            FinlError::DuplicateKey(..) => "F0015",
+            FinlError::MissingKeyValueSeparator(..) => "F0016",
+            FinlError::UnknownParameterFormat(..) => "F0017",
+            FinlError::UnknownParameterType(..) => "F0018",
+            FinlError::InvalidDefinition(..) => "F0019",
+            FinlError::UndefinedVariable(..) => "F0020",
+            FinlError::ArgumentOutOfRange(..) => "F0021",
+            FinlError::UndefinedMethod(..) => "F0022",
+            FinlError::InvalidMethodArguments(..) => "F0023",
+            FinlError::CommandAlreadyDefined(..) => "F0024",
+            FinlError::ExpansionTooDeep(..) => "F0025",
+            FinlError::TooManyExpansions(..) => "F0026",
+            FinlError::UnclosedGroup(..) => "F0027",
+            FinlError::EnvironmentEndWhileParsingCommandArguments(..) => "F0028",
+            FinlError::MissingArgumentDelimiter(..) => "F0029",
+        }
+    }
+
+    // A warning is something we could carry on from without losing any of the input
+    pub fn severity(&self) -> Severity {
+        match self {
+            FinlError::DuplicateKey(..) => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        let context = self.context();
+        Diagnostic {
+            code: self.code(),
+            severity: self.severity(),
+            message: self.to_string(),
+            primary: Span {
+                location: context.location.clone(),
+                length: self.span_length(),
+                label: None,
+            },
+            secondary: context.expanded_from.iter()
+                .map(|location| Span {
+                    location: location.clone(),
+                    length: 1,
+                    label: Some("macro used here".to_string()),
+                })
+                .collect(),
+            help: self.help(),
+        }
+    }
+
+    // A rendering for the terminal in the style of rustc, e.g.,
+    //
+    //     error[F0001]: undefined command `\foo`
+    //      --> chapter.tex:3:6
+    //       |
+    //     3 | Some \foo text
+    //       |      ^^^^
+    pub fn render(&self) -> String {
+        let diagnostic = self.diagnostic();
+        let location = &diagnostic.primary.location;
+        let line = &self.context().line_contents;
+        let mut rendered = format!("{}[{}]: {}\n", diagnostic.severity, diagnostic.code, diagnostic.message);
+        let gutter = " ".repeat(location.line_number.to_string().len());
+        rendered.push_str(&format!("{}--> {}\n", gutter, position(location, line)));
+        // We don't always have the line, e.g., for a token from an earlier line
+        if !line.is_empty() {
+            let before = line.get(..location.column).unwrap_or(line);
+            // Tabs are kept so the marker lines up
+            let indent: String = before.graphemes(true).map(|grapheme| if grapheme == "\t" { '\t' } else { ' ' }).collect();
+            let available = line[before.len()..].graphemes(true).count().max(1);
+            let underline = "^".repeat(diagnostic.primary.length.min(available));
+            rendered.push_str(&format!("{} |\n", gutter));
+            rendered.push_str(&format!("{} | {}\n", location.line_number, line));
+            rendered.push_str(&format!("{} | {}{}\n", gutter, indent, underline));
+        }
+        for span in &diagnostic.secondary {
+            let label = span.label.as_deref().unwrap_or("see");
+            rendered.push_str(&format!("{} = note: {}: {}\n", gutter, label, position(&span.location, "")));
+        }
+        for help in &diagnostic.help {
+            rendered.push_str(&format!("{} = help: {}\n", gutter, help));
+        }
+        rendered
+    }
+
+    // How many graphemes the error is about, starting at its location
+    fn span_length(&self) -> usize {
+        match self {
+            FinlError::UndefinedCommand(_, name, _) | FinlError::CommandAlreadyDefined(_, name) =>
+                name.graphemes(true).count() + 1,
+            FinlError::UndefinedEnvironment(_, name, _) => name.graphemes(true).count(),
+            FinlError::UnclosedGroup(_, GroupType::Environment(_)) => "\\begin".len(),
+            FinlError::EnvironmentEndWhileParsingCommandArguments(..) => "\\end".len(),
+            _ => 1,
+        }
+    }
+
+    fn help(&self) -> Vec<String> {
+        let mut help = match self {
+            FinlError::UndefinedCommand(_, _, similar) =>
+                did_you_mean(&similar.iter().map(|name| format!("`\\{}`", name)).collect::<Vec<_>>()),
+            FinlError::UndefinedEnvironment(_, _, similar) =>
+                did_you_mean(&similar.iter().map(|name| format!("`{}`", name)).collect::<Vec<_>>()),
+            _ => Vec::new(),
+        };
+        let general = match self {
+            FinlError::UndefinedCommand(..) =>
+                "commands are defined with `\\newcommand` or in a definition file",
+            FinlError::BlankLineWhileParsingCommandArguments(..) => "arguments can't contain blank lines",
+            FinlError::MissingBracesForCommandArgument(..) => "put the argument in braces",
+            FinlError::UnexpectedEOLWhileParsingCommandArguments(_, _, _, GroupType::ArbitraryDelim(_)) =>
+                "this kind of argument has to be on one line",
+            FinlError::MissingArgumentDelimiter(..) =>
+                "this kind of argument starts with a delimiter on the command's line and ends at the next one",
+            FinlError::InsufficientIndentationInEnvironment(..) =>
+                "lines in the environment must be indented at least as much as its `\\end`",
+            FinlError::InvalidKey(..) => "keys must be identifiers",
+            FinlError::DuplicateKey(..) => "only the first value is used",
+            FinlError::UnknownParameterFormat(..) =>
+                "the formats are `star`, `required`, `required-with-braces`, `optional` and `arbitrary-delimiters`",
+            FinlError::UnknownParameterType(..) =>
+                "the types are `parsed`, `verbatim`, `boolean`, `key-value`, `macro`, `math` and `yaml`",
+            FinlError::ExpansionTooDeep(..) => "if this is intended, raise the limit with `Parser::set_max_expansion_depth`",
+            FinlError::TooManyExpansions(..) =>
+                "if this is intended, raise the limits with `Parser::set_max_expansions` and `Parser::set_max_expansion_tokens`",
+            _ => return help,
+        };
+        help.push(general.to_string());
+        help
+    }
+}
+
+fn did_you_mean(names: &[String]) -> Vec<String> {
+    match names {
+        [] => Vec::new(),
+        [name] => vec![format!("did you mean {}?", name)],
+        [names @ .., last] => vec![format!("did you mean {} or {}?", names.join(", "), last)],
+    }
+}
+
+// Columns are shown counting graphemes from 1. Without the line, the best we can do is the byte offset.
+fn position(location: &Location, line: &str) -> String {
+    let column = if line.is_empty() {
+        location.column + 1
+    }
+    else {
+        line.get(..location.column).unwrap_or(line).graphemes(true).count() + 1
+    };
+    format!("{}:{}:{}", location.file, location.line_number, column)
+}
@@ -0,0 +1,96 @@
+use std::collections::BTreeMap;
+
+use crate::context::ExecutionContext;
+use crate::implementation::ExpandedArgument;
+use crate::tokens::{ErrorContext, FinlError, Token};
+
+// Font attributes by axis, e.g., `series` → `b`
+pub type FontAttributes = BTreeMap<String, String>;
+
+// The change a font command makes to the attributes in effect
+#[derive(Clone, Debug, PartialEq)]
+pub struct Font {
+    pub axis: String,
+    pub value: String,
+    // Everything in effect for the text, including this change
+    pub attributes: FontAttributes,
+}
+
+// Font declarations last until the end of the enclosing brace group or argument. The parser opens and closes a scope
+// for each `GroupType::Brace`, required and optional argument, and token list argument of a replayed macro body.
+#[derive(Debug)]
+pub struct FontStack {
+    scopes: Vec<FontAttributes>,
+}
+
+impl Default for FontStack {
+    fn default() -> Self {
+        FontStack {
+            scopes: vec![FontAttributes::new()]
+        }
+    }
+}
+
+impl FontStack {
+    pub fn current(&self) -> &FontAttributes {
+        self.scopes.last().expect("the outermost scope is never closed")
+    }
+
+    pub fn set(&mut self, axis: &str, value: &str) {
+        self.scopes.last_mut()
+            .expect("the outermost scope is never closed")
+            .insert(axis.to_string(), value.to_string());
+    }
+
+    pub fn begin_group(&mut self) {
+        self.scopes.push(self.current().clone());
+    }
+
+    pub fn end_group(&mut self) {
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+}
+
+// `internal:font` takes an axis, a value and, optionally, the text to set in that font. Without text, the value
+// is declared for the rest of the enclosing brace group or argument.
+pub fn font(context: &mut ExecutionContext, error_context: &ErrorContext, _command: &str, args: Vec<ExpandedArgument>) -> Result<Vec<Token>, FinlError> {
+    let mut args = args.into_iter();
+    match (args.next(), args.next(), args.next(), args.next()) {
+        (Some(ExpandedArgument::Text(axis)), Some(ExpandedArgument::Text(value)), None, None) => {
+            context.get_or_default::<FontStack>().set(&axis, &value);
+            Ok(vec![])
+        }
+        (Some(ExpandedArgument::Text(axis)), Some(ExpandedArgument::Text(value)), Some(text), None) => {
+            let location = error_context.location.clone();
+            let text = match text {
+                ExpandedArgument::Argument(Token::Tokens(_, tokens)) => tokens,
+                ExpandedArgument::Argument(token) => vec![token],
+                ExpandedArgument::Text(text) => vec![Token::ParsedText(location.clone(), text)],
+            };
+            let mut attributes = context.get_or_default::<FontStack>().current().clone();
+            attributes.insert(axis.clone(), value.clone());
+            // Font commands inside the text were executed first, so they didn't see this change
+            let text = text.into_iter().map(|token| resolve(token, &attributes)).collect();
+            Ok(vec![Token::Font(location, Font { axis, value, attributes }, text)])
+        }
+        _ => Err(FinlError::InvalidMethodArguments(error_context.clone(), "internal:font".to_string(),
+                                                   "an axis, a value and optionally the text".to_string()))
+    }
+}
+
+fn resolve(token: Token, outer: &FontAttributes) -> Token {
+    match token {
+        Token::Font(location, font, tokens) => {
+            let mut attributes = outer.clone();
+            attributes.insert(font.axis.clone(), font.value.clone());
+            let tokens = tokens.into_iter().map(|token| resolve(token, &attributes)).collect();
+            Token::Font(location, Font { attributes, ..font }, tokens)
+        }
+        Token::Tokens(location, tokens) => {
+            Token::Tokens(location, tokens.into_iter().map(|token| resolve(token, outer)).collect())
+        }
+        token => token,
+    }
+}
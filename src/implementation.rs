@@ -0,0 +1,91 @@
+use crate::tokens::{ErrorContext, FinlError, Token};
+use crate::variables::VariableStore;
+
+// How a command defined in a definition file is carried out: the method to call and its arguments. Arguments can
+// refer to variables with `${path}` and to the command's own arguments with `$1`, `$2`, etc. `$$` is a literal `$`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Implementation {
+    pub method: String,
+    pub arguments: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExpandedArgument {
+    Text(String),
+    // An argument which is exactly `$n` gets the command's argument itself
+    Argument(Token),
+}
+
+impl Implementation {
+    pub fn new(method: &str, arguments: Vec<String>) -> Implementation {
+        Implementation {
+            method: method.to_string(),
+            arguments
+        }
+    }
+
+    // `error_context` is where the command was used
+    pub fn expand(&self, command: &str, variables: &VariableStore, args: &[Token], error_context: &ErrorContext) -> Result<Vec<ExpandedArgument>, FinlError> {
+        self.arguments.iter()
+            .map(|argument| expand_argument(argument, command, variables, args, error_context))
+            .collect()
+    }
+}
+
+fn expand_argument(argument: &str, command: &str, variables: &VariableStore, args: &[Token], error_context: &ErrorContext) -> Result<ExpandedArgument, FinlError> {
+    if let Some(number) = argument.strip_prefix('$').and_then(|number| number.parse::<usize>().ok()) {
+        return positional_argument(number, command, args, error_context).cloned().map(ExpandedArgument::Argument);
+    }
+    let mut text = String::new();
+    let mut rest = argument;
+    while let Some(index) = rest.find('$') {
+        text.push_str(&rest[..index]);
+        rest = &rest[index + 1..];
+        if let Some(after_brace) = rest.strip_prefix('{') {
+            match after_brace.find('}') {
+                Some(end) => {
+                    let path = &after_brace[..end];
+                    let value = variables.get_value(path).ok_or_else(|| {
+                        FinlError::UndefinedVariable(error_context.clone(), command.to_string(), path.to_string())
+                    })?;
+                    text.push_str(value);
+                    rest = &after_brace[end + 1..];
+                }
+                // Without a closing brace, we take things literally
+                None => text.push('$'),
+            }
+        }
+        else if let Some(after_dollar) = rest.strip_prefix('$') {
+            text.push('$');
+            rest = after_dollar;
+        }
+        else {
+            let digits = rest.len() - rest.trim_start_matches(|ch: char| ch.is_ascii_digit()).len();
+            if digits == 0 {
+                text.push('$');
+            }
+            else {
+                let number = rest[..digits].parse().unwrap_or(usize::MAX);
+                text.push_str(&plain_text(positional_argument(number, command, args, error_context)?));
+                rest = &rest[digits..];
+            }
+        }
+    }
+    text.push_str(rest);
+    Ok(ExpandedArgument::Text(text))
+}
+
+fn positional_argument<'t>(number: usize, command: &str, args: &'t [Token], error_context: &ErrorContext) -> Result<&'t Token, FinlError> {
+    number.checked_sub(1)
+        .and_then(|index| args.get(index))
+        .ok_or_else(|| FinlError::ArgumentOutOfRange(error_context.clone(), command.to_string(), number))
+}
+
+// An argument filled into text is taken as the text it contains, without its braces
+fn plain_text(token: &Token) -> String {
+    match token {
+        Token::Tokens(_, tokens) | Token::Font(_, _, tokens) => tokens.iter().map(plain_text).collect(),
+        Token::Bgroup(_) | Token::Egroup(_) => String::new(),
+        token => token.to_string(),
+    }
+}
@@ -0,0 +1,170 @@
+use std::ops::Range;
+
+use linked_hash_map::LinkedHashMap;
+use unicode_xid::UnicodeXID;
+
+use crate::Parser;
+use crate::tokens::{Token, Location, FinlError};
+
+impl<'a> Parser<'a> {
+    // A key-value argument is parsed like any other argument and then broken up at the commas and equals signs
+    // which aren't inside braces.
+    pub(crate) fn key_value_list_from_argument(&mut self, argument: Token) -> Token {
+        match argument {
+            Token::Tokens(location, tokens) => self.key_value_list(location, tokens),
+            token => self.key_value_list(token.location().clone(), vec![token]),
+        }
+    }
+
+    // Entries with a bad key are reported and dropped. If a key appears twice, the first value wins.
+    fn key_value_list(&mut self, location: Location, tokens: Vec<Token>) -> Token {
+        let mut list = LinkedHashMap::new();
+        for entry in self.split_tokens(tokens, ',', usize::MAX) {
+            let entry = self.trim_tokens(entry);
+            // Empty entries come from trailing (or doubled) commas
+            if entry.is_empty() {
+                continue;
+            }
+            let entry_location = entry[0].location().clone();
+            let mut parts = self.split_tokens(entry, '=', 1).into_iter();
+            let key = self.trim_tokens(parts.next().unwrap_or_default());
+            let key = match key.as_slice() {
+                [Token::ParsedText(_, text)] if is_identifier(text) => text.clone(),
+                _ => {
+                    let key_text: String = key.iter().map(|token| token.to_string()).collect();
+                    let key_location = key.first().map_or(&entry_location, |token| token.location());
+                    self.push_error(FinlError::InvalidKey(self.error_context_at(key_location), key_text));
+                    continue;
+                }
+            };
+            let value = match parts.next() {
+                Some(value) => strip_outer_braces(self.trim_tokens(value)),
+                None => {
+                    self.push_error(FinlError::MissingKeyValueSeparator(self.error_context_at(&entry_location), key));
+                    continue;
+                }
+            };
+            if list.contains_key(&key) {
+                self.push_error(FinlError::DuplicateKey(self.error_context_at(&entry_location), key));
+                continue;
+            }
+            list.insert(key, value);
+        }
+        Token::KeyValueList(location, list)
+    }
+
+    // Split at `separator` wherever it appears in text outside of braces, at most `max_splits` times
+    fn split_tokens(&self, tokens: Vec<Token>, separator: char, max_splits: usize) -> Vec<Vec<Token>> {
+        let mut pieces = vec![Vec::new()];
+        let mut depth = 0usize;
+        for token in tokens {
+            match token {
+                Token::ParsedText(location, text) if depth == 0 && pieces.len() <= max_splits => {
+                    let mut piece_start = 0;
+                    for (offset, _) in text.match_indices(separator) {
+                        if pieces.len() > max_splits {
+                            break;
+                        }
+                        self.push_text(pieces.last_mut().unwrap(), &location, &text, piece_start..offset);
+                        pieces.push(Vec::new());
+                        piece_start = offset + separator.len_utf8();
+                    }
+                    self.push_text(pieces.last_mut().unwrap(), &location, &text, piece_start..text.len());
+                }
+                token => {
+                    match token {
+                        Token::Bgroup(_) => depth += 1,
+                        Token::Egroup(_) => depth = depth.saturating_sub(1),
+                        _ => {}
+                    }
+                    pieces.last_mut().unwrap().push(token);
+                }
+            }
+        }
+        pieces
+    }
+
+    fn push_text(&self, tokens: &mut Vec<Token>, location: &Location, text: &str, range: Range<usize>) {
+        if !range.is_empty() {
+            tokens.push(Token::ParsedText(self.text_location(location, text, range.start), text[range].to_string()));
+        }
+    }
+
+    // Remove leading and trailing spaces
+    fn trim_tokens(&self, mut tokens: Vec<Token>) -> Vec<Token> {
+        while let Some(Token::ParsedText(location, text)) = tokens.first() {
+            let trimmed = text.trim_start();
+            if trimmed.is_empty() {
+                tokens.remove(0);
+            }
+            else {
+                tokens[0] = Token::ParsedText(self.text_location(location, text, text.len() - trimmed.len()),
+                                              trimmed.to_string());
+                break;
+            }
+        }
+        while let Some(Token::ParsedText(location, text)) = tokens.last() {
+            let trimmed = text.trim_end();
+            if trimmed.is_empty() {
+                tokens.pop();
+            }
+            else {
+                let last = tokens.len() - 1;
+                tokens[last] = Token::ParsedText(location.clone(), trimmed.to_string());
+                break;
+            }
+        }
+        tokens
+    }
+
+    // Where the char at `offset` in `text` is, for text which starts at `location`. Columns in the line are for the
+    // text as it was written, which may not be normalized, and a space in the text may be a run of spaces in the
+    // line, so we find the char in the line. If the text isn't from the line (say, it came from a default), the best
+    // we can do is the start of the text.
+    fn text_location(&self, location: &Location, text: &str, offset: usize) -> Location {
+        if offset == 0 || location.line_number != self.line.line_number {
+            return location.clone();
+        }
+        let start = self.line.normalized_column(location.column);
+        let mut line = self.line.contents[start..].char_indices().peekable();
+        for ch in text[..offset].chars() {
+            match line.next() {
+                Some((_, ' ' | '\t')) if ch == ' ' => {
+                    while line.next_if(|(_, line_ch)| *line_ch == ' ' || *line_ch == '\t').is_some() {}
+                }
+                Some((_, line_ch)) if line_ch == ch => {}
+                _ => return location.clone(),
+            }
+        }
+        let column = line.next().map_or(self.line.contents.len(), |(index, _)| start + index);
+        Location::from_line_and_column(&self.line, column)
+    }
+}
+
+// `{ e }` becomes ` e `, but `{a}{b}` is left alone.
+fn strip_outer_braces(mut tokens: Vec<Token>) -> Vec<Token> {
+    if let (Some(Token::Bgroup(_)), Some(Token::Egroup(_))) = (tokens.first(), tokens.last()) {
+        let mut depth = 0usize;
+        for (index, token) in tokens.iter().enumerate() {
+            match token {
+                Token::Bgroup(_) => depth += 1,
+                Token::Egroup(_) => {
+                    depth -= 1;
+                    if depth == 0 && index < tokens.len() - 1 {
+                        return tokens;
+                    }
+                }
+                _ => {}
+            }
+        }
+        tokens.pop();
+        tokens.remove(0);
+    }
+    tokens
+}
+
+// Keys must be UAX31 identifiers
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars.next().is_some_and(UnicodeXID::is_xid_start) && chars.all(UnicodeXID::is_xid_continue)
+}
@@ -1,39 +1,64 @@
-#![feature(assert_matches)]
+// Errors carry enough context to be reported on their own, which makes them larger than clippy likes
+#![allow(clippy::result_large_err)]
+
 use std::collections::HashMap;
 use std::iter::Peekable;
 use std::rc::Rc;
-use std::str::CharIndices;
+use std::str::Lines;
+use std::vec;
 
 use unicode_categories::UnicodeCategories;
-
-use crate::commands::{Command, Environment, ParameterFormat, ParameterType};
+use unicode_segmentation::UnicodeSegmentation;
+use yaml_rust2::{Yaml, YamlLoader};
+
+use crate::commands::{Command, CommandHandler, Environment, EnvironmentHandler, ParameterFormat, ParameterType};
+use crate::context::ExecutionContext;
+use crate::definitions::{marker_column, yaml_error_message};
+use crate::font::FontStack;
+use crate::implementation::Implementation;
+use crate::macros::Expansion;
+use crate::methods::InternalMethod;
 use crate::tokens::{Token, Location, Line, FinlError, GroupType, ErrorContext};
 use std::mem;
 
-mod tokens;
-mod commands;
-
-enum ParserState {
-    StartingCommand(usize),
-    NamedCommand(String, Rc<Location>),
-    SymbolCommand(String, Rc<Location>),
-    ParsingText(usize),
-    ParsingComment(usize),
-    IgnoringSpacesAfterNamedCommand(Rc<Command>, Rc<Location>),
-    // We've identified a command, we're now looking for its arguments
-    // .0 has the command whose arguments we're parsing,
-    // .1 is the index into command.parameters
-    // .2 is the Location of the beginning of the command
-    ParsingArguments(Rc<Command>, usize, Rc<Location>),
-}
-
-
+pub mod tokens;
+pub mod commands;
+pub mod context;
+pub mod implementation;
+pub mod methods;
+pub mod font;
+pub mod variables;
+mod key_value;
+mod definitions;
+pub mod diagnostics;
+mod macros;
+mod normalize;
+mod suggestions;
+
+#[allow(dead_code)] // Math is not yet handled
+#[derive(Clone, Copy)]
 enum CommandContext {
     Text,
     UserCommandDefinition,
     Math,
 }
 
+// Settings that have to be made before any input is read
+#[derive(Clone, Copy, Debug)]
+pub struct ParserOptions {
+    // Input is normalized to NFD unless it's known to be normalized already. Locations always point into the text
+    // as it was written.
+    pub normalize: bool,
+}
+
+impl Default for ParserOptions {
+    fn default() -> Self {
+        ParserOptions {
+            normalize: true
+        }
+    }
+}
+
 #[derive(PartialEq,Debug)]
 enum SkipWhiteSpaceOutcome {
     Skipped,
@@ -42,25 +67,36 @@ enum SkipWhiteSpaceOutcome {
 }
 
 
-struct Parser<'a> {
+pub struct Parser<'a> {
     commands: HashMap<String, Rc<Command>>,
     environments: HashMap<String, Rc<Environment>>,
-    lines: Box<dyn Iterator<Item=&'a str> + 'a>,
+    methods: HashMap<String, Rc<dyn InternalMethod>>,
+    lines: Peekable<Lines<'a>>,
     line: Line,
-    char_iterator: Peekable<CharIndices<'a>>,
+    char_iterator: Peekable<vec::IntoIter<(usize, char)>>,
+    // Once the input has ended, `line` is still the last line so errors can point at its end
+    at_end: bool,
+    normalize: bool,
     output: Vec<Result<Token, FinlError>>,
-    stack: Vec<GroupType>,
+    stack: Vec<(GroupType, Location)>, // open groups and where they were opened, innermost last
+    context: ExecutionContext,
+    expansion: Expansion,
 }
 impl<'a> Default for Parser<'a> {
     fn default() -> Self {
         Parser {
             commands: Default::default(),
             environments: Default::default(),
-            lines: Box::new("".lines()),
+            methods: methods::internal_methods(),
+            lines: "".lines().peekable(),
             line: Default::default(),
-            char_iterator: "".char_indices().peekable(),
+            char_iterator: Vec::new().into_iter().peekable(),
+            at_end: false,
+            normalize: true,
             output: vec![],
-            stack: vec![]
+            stack: vec![],
+            context: Default::default(),
+            expansion: Default::default()
         }
     }
 }
@@ -68,18 +104,28 @@ impl<'a> Default for Parser<'a> {
 
 impl<'a> Parser<'a> {
     pub fn from_string(input: &'a str) -> Parser<'a> {
+        Parser::with_options(input, ParserOptions::default())
+    }
+
+    pub fn with_options(input: &'a str, options: ParserOptions) -> Parser<'a> {
         let mut context :Parser<'a> = Parser {
             commands: Default::default(),
             environments: Default::default(),
-            lines: Box::new(input.lines()),
+            methods: methods::internal_methods(),
+            lines: input.lines().peekable(),
             line: Line {
                 file: "STRING CONSTANT".to_string(),
                 line_number: 0,
-                contents: Default::default()
+                contents: Default::default(),
+                original: None
             },
-            char_iterator: "".char_indices().peekable(),
+            char_iterator: Vec::new().into_iter().peekable(),
+            at_end: false,
+            normalize: options.normalize,
             output: vec![],
-            stack: vec![]
+            stack: vec![],
+            context: Default::default(),
+            expansion: Default::default()
         };
         context.next_line();
         context
@@ -89,83 +135,223 @@ impl<'a> Parser<'a> {
         self.commands.insert(name.to_string(), Rc::new(Command::new(name, args)));
     }
 
+    pub fn define_command_with_handler(&mut self, name: &str, args: Vec<(ParameterFormat, ParameterType)>, handler: impl CommandHandler + 'static) {
+        self.commands.insert(name.to_string(), Rc::new(Command::with_handler(name, args, Rc::new(handler))));
+    }
+
+    pub fn define_environment(&mut self, name: &str, args: Vec<(ParameterFormat, ParameterType)>, body_type: ParameterType) {
+        self.environments.insert(name.to_string(), Rc::new(Environment::new(name, args, body_type)));
+    }
+
+    pub fn define_environment_with_handler(&mut self, name: &str, args: Vec<(ParameterFormat, ParameterType)>, body_type: ParameterType, handler: impl EnvironmentHandler + 'static) {
+        self.environments.insert(name.to_string(),
+                                 Rc::new(Environment::with_handler(name, args, body_type, Rc::new(handler))));
+    }
+
+    pub fn define_method(&mut self, name: &str, method: impl InternalMethod + 'static) {
+        self.methods.insert(name.to_string(), Rc::new(method));
+    }
+
+    // How many macros may be expanded inside one another
+    pub fn set_max_expansion_depth(&mut self, depth: usize) {
+        self.expansion.max_depth = depth;
+    }
+
+    // How many macros one macro used in the document may expand into, counting itself
+    pub fn set_max_expansions(&mut self, count: usize) {
+        self.expansion.max_expansions = count;
+    }
+
+    // How many tokens, counting those inside others, the macros in one macro used in the document may substitute
+    pub fn set_max_expansion_tokens(&mut self, count: usize) {
+        self.expansion.max_tokens = count;
+    }
+
+    // The state shared by handlers, e.g., to set it up before parsing or look at it afterwards
+    pub fn context(&mut self) -> &mut ExecutionContext {
+        &mut self.context
+    }
+
     pub fn parse(&mut self) -> Vec<Result<Token, FinlError>> {
-        self.text_parse();
+        self.text_parse(CommandContext::Text);
         mem::take(&mut self.output)
     }
 
-    fn push_eol_text_block(&mut self, start: usize) {
-        self.push_text_block(start, self.line.contents.len());
+    // `space` is whether the line end counts as a space, which runs together with any spaces before it. As in TeX,
+    // there's no space at the start of a paragraph.
+    fn push_eol_text_block(&mut self, start: usize, space: bool) {
+        let mut text = collapse_spaces(&self.line.contents[start..]);
+        if space && !text.ends_with(' ') && !(text.is_empty() && self.at_paragraph_start()) {
+            text.push(' ');
+        }
+        if !text.is_empty() {
+            self.output.push(Ok(Token::ParsedText(Location::from_line_and_column(&self.line, start), text)));
+        }
+    }
+
+    // Nothing but errors has been output since the start of the input or the last paragraph break
+    fn at_paragraph_start(&self) -> bool {
+        matches!(self.output.iter().rev().find_map(|item| item.as_ref().ok()), None | Some(Token::ParagraphBreak(_)))
     }
+
     fn push_text_block(&mut self, start: usize, end: usize) {
         if start != end {
             self.output.push(Ok(Token::ParsedText(Location::from_line_and_column(&self.line, start),
-                                              self.line.contents.get(start..end).unwrap().to_string())));
+                                              collapse_spaces(self.line.contents.get(start..end).unwrap()))));
         }
     }
-    fn push_error(&mut self, error: FinlError) {
+    // An error that comes up while expanding a macro gets the places the macros were used
+    fn push_error(&mut self, mut error: FinlError) {
+        let context = error.context_mut();
+        if context.expanded_from.is_empty() {
+            context.expanded_from = self.expansion.chain.iter().rev().map(|(_, location)| location.clone()).collect();
+        }
         self.output.push(Err(error));
     }
 
-    fn push_command(&mut self, command: Rc<Command>, args: Vec<Token>, column: usize) {
-        self.push_token(Token::Command(Location::from_line_and_column(&self.line, column),command, args))
+    // A command with a handler is replaced by whatever the handler returns
+    // a command with an implementation by whatever its method returns and a macro by its expansion
+    fn push_command(&mut self, command: Rc<Command>, args: Vec<Token>, location: Location) {
+        match (&command.handler, &command.implementation, &command.body) {
+            (Some(handler), _, _) => {
+                let result = handler.execute(&mut self.context, &location, args);
+                self.push_handler_result(result);
+            }
+            (None, Some(implementation), _) => {
+                let result = self.execute_implementation(&command.name, implementation, args, &location);
+                self.push_handler_result(result);
+            }
+            (None, None, Some(body)) => self.expand_macro(&command.name, body, &args, &location),
+            (None, None, None) => self.push_token(Token::Command(location, command, args)),
+        }
+    }
+
+    fn execute_implementation(&mut self, command: &str, implementation: &Implementation, args: Vec<Token>, location: &Location) -> Result<Vec<Token>, FinlError> {
+        let error_context = self.error_context_at(location);
+        let method = self.methods.get(&implementation.method).cloned().ok_or_else(|| {
+            FinlError::UndefinedMethod(error_context.clone(), command.to_string(), implementation.method.clone())
+        })?;
+        let args = implementation.expand(command, &self.context.variables, &args, &error_context)?;
+        method.execute(&mut self.context, &error_context, command, args)
+    }
+
+    fn push_environment(&mut self, environment: Rc<Environment>, args: Vec<Token>, body: Vec<Token>, location: Location) {
+        match &environment.handler {
+            None => self.push_token(Token::Environment(location, environment, args, body)),
+            Some(handler) => {
+                let result = handler.execute(&mut self.context, &location, args, body);
+                self.push_handler_result(result);
+            }
+        }
+    }
+
+    fn push_handler_result(&mut self, result: Result<Vec<Token>, FinlError>) {
+        match result {
+            Ok(tokens) => {
+                for token in tokens {
+                    self.push_token(token);
+                }
+            }
+            Err(error) => self.push_error(error),
+        }
     }
 
     fn push_token(&mut self, token: Token) {
         self.output.push(Ok(token))
     }
 
+    // A blank line is left unread so that it still breaks the paragraph once the caller has reported it
     fn skip_whitespace(&mut self) -> SkipWhiteSpaceOutcome {
-        let mut new_line_count = 0usize;
         loop {
             match self.char_iterator.peek().cloned() {
                 None => {
-                    if self.next_line() {
-                        new_line_count += 1;
-                    }
-                    else {
+                    if !self.next_line() {
                         return SkipWhiteSpaceOutcome::EndOfFile;
                     }
+                    if self.on_blank_line() {
+                        return SkipWhiteSpaceOutcome::FoundBlankLine;
+                    }
                 }
                 Some((_, ch)) => {
                     if ch.is_whitespace() {
                         self.char_iterator.next();
                     }
                     else {
-                        break;
+                        return SkipWhiteSpaceOutcome::Skipped;
                     }
                 }
             }
         }
+    }
+
+    // Once the input has ended, we're not on any line
+    fn on_blank_line(&self) -> bool {
+        !self.at_end && self.line.line_number > 0 && self.line.contents.trim().is_empty()
+    }
 
-        if new_line_count > 1 {
-            SkipWhiteSpaceOutcome::FoundBlankLine
+    // Arguments can't contain blank lines, so at one we return true to have text_parse stop and leave the caller
+    // to report the unclosed argument. Anywhere else, a run of blank lines is a paragraph break, unless there's no
+    // paragraph to break yet.
+    fn blank_line_parse(&mut self) -> bool {
+        if matches!(self.open_group(), Some(GroupType::RequiredArgument | GroupType::OptionalArgument)) {
+            return true;
         }
-        else {
-            SkipWhiteSpaceOutcome::Skipped
+        if !self.at_paragraph_start() {
+            self.push_token(Token::ParagraphBreak(Location::from_line_and_column(&self.line, 0)));
+        }
+        while self.lines.peek().is_some_and(|line| line.trim().is_empty()) {
+            self.next_line();
         }
+        self.skip_to_end_of_line();
+        false
     }
 
     // Get the next line. Return false if EOF on input
     fn next_line(&mut self) -> bool {
         match self.lines.next() {
             None => {
-                self.line = Line::default();
-                self.char_iterator = "".char_indices().peekable();
+                self.at_end = true;
+                self.char_iterator = Vec::new().into_iter().peekable();
                 false
             }
             Some(line) => {
                 self.line.line_number += 1;
-                self.line.contents = String::from(line);
-                self.char_iterator = line.char_indices().peekable();
+                match normalize::nfd(line).filter(|_| self.normalize) {
+                    Some((contents, original)) => {
+                        self.line.contents = contents;
+                        self.line.original = Some(original);
+                    }
+                    None => {
+                        self.line.contents = String::from(line);
+                        self.line.original = None;
+                    }
+                }
+                self.char_iterator = self.line.contents.char_indices().collect::<Vec<_>>().into_iter().peekable();
                 true
             }
         }
     }
 
     fn undefined_command(&self, command_name: String, column: usize) -> FinlError {
+        let similar = suggestions::similar_names(&command_name, self.commands.keys());
         FinlError::UndefinedCommand(ErrorContext::from_line_and_column(&self.line, column),
-                                    command_name)
+                                    command_name,
+                                    similar)
+    }
+
+    // Tokens can come from earlier lines whose contents we no longer have
+    fn error_context_at(&self, location: &Location) -> ErrorContext {
+        let line_contents = if location.line_number == self.line.line_number {
+            self.line.original_contents().to_string()
+        }
+        else {
+            String::new()
+        };
+        ErrorContext {
+            location: location.clone(),
+            line_contents,
+            expanded_from: Vec::new()
+        }
     }
 
     fn unimplemented(&self, column: usize) -> FinlError {
@@ -180,8 +366,9 @@ impl<'a> Parser<'a> {
     }
 
     // no column passed because it's always 0
+    // no column passed because it's always the end of the last line
     fn unexpected_eof_while_parsing_command_arguments(&self, command_name: String, arg_number: usize) -> FinlError {
-        FinlError::UnexpectedEOFWhileParsingCommandArguments(ErrorContext::from_line_and_column(&self.line, 0),
+        FinlError::UnexpectedEOFWhileParsingCommandArguments(ErrorContext::from_line_and_column(&self.line, self.line.contents.len()),
                                                              command_name,
                                                              arg_number)
     }
@@ -191,222 +378,843 @@ impl<'a> Parser<'a> {
     }
 
 
-    fn text_parse(&mut self) {
-        if let Some((0, _)) = self.char_iterator.peek().clone() {
-            // Skip leading whitespace at beginnings of lines
-            //context.char_iterator.skip_while(|(_, ch)| ch.is_whitespace());
-            self.skip_whitespace();
+    fn close_brace_while_parsing_command_arguments(&self, command_name: String, arg_number: usize, column: usize) -> FinlError {
+        FinlError::CloseBraceWhileParsingCommandArguments(ErrorContext::from_line_and_column(&self.line, column),
+                                                          command_name,
+                                                          arg_number)
+    }
+
+    fn environment_end_while_parsing_command_arguments(&self, command_name: String, arg_number: usize, column: usize) -> FinlError {
+        FinlError::EnvironmentEndWhileParsingCommandArguments(ErrorContext::from_line_and_column(&self.line, column),
+                                                              command_name,
+                                                              arg_number)
+    }
+
+    fn missing_braces_for_command_argument(&self, command_name: String, arg_number: usize, column: usize) -> FinlError {
+        FinlError::MissingBracesForCommandArgument(ErrorContext::from_line_and_column(&self.line, column),
+                                                   command_name,
+                                                   arg_number)
+    }
+
+    // A delimited argument stops at a blank line or the end of input. At the end of input, we point at where the
+    // argument was opened.
+    fn unclosed_argument(&mut self, command: &str, parameter_number: usize, location: &Location) -> FinlError {
+        if self.lines.peek().is_none() && self.char_iterator.peek().is_none() {
+            FinlError::UnexpectedEOFWhileParsingCommandArguments(self.error_context_at(location),
+                                                                 command.to_string(),
+                                                                 parameter_number)
         }
-        let mut start;
-        match self.char_iterator.peek() {
-            None => {
-                return;
-            }
-            Some((column, _)) => {
-                start = *column;
-            }
+        else {
+            self.blank_line_while_parsing_command_arguments(command.to_string(), parameter_number)
         }
-        while let Some((column, ch)) = self.char_iterator.peek().cloned() {
-            match ch {
-                '\\' => {
-                    self.push_text_block(start, column);
-                    self.command_parse(CommandContext::Text);
-                    start = self.get_column();
-                }
-                // If we have a `%`, we dump whatever's left and finish the line.
-                '%' => {
-                    self.push_text_block(start, column);
-                    self.next_line();
-                    return ;
-                }
-                '{' => {
-                    self.push_text_block(start, column);
-                    self.push_token(Token::Bgroup(Location::from_line_and_column(&self.line, column)));
-                    self.char_iterator.next();
-                    self.stack.push(GroupType::Brace);
-                    start = self.get_column();
-                }
-                '}' => {
-                    self.push_text_block(start, column);
-                    let top_of_stack = self.stack.last().cloned();
-                    if let Some(GroupType::Brace) = self.stack.pop() {
-                        self.push_token(Token::Egroup(Location::from_line_and_column(&self.line, column)));
-                    }
-                    else if top_of_stack == Some(GroupType::RequiredArgument) {
-                        return
+    }
+
+    fn undefined_environment(&self, environment_name: String, column: usize) -> FinlError {
+        let similar = suggestions::similar_names(&environment_name, self.environments.keys());
+        FinlError::UndefinedEnvironment(ErrorContext::from_line_and_column(&self.line, column), environment_name, similar)
+    }
+
+    fn mismatched_environment_end(&self, open_environment: String, close_environment: String, column: usize) -> FinlError {
+        FinlError::MismatchedEnvironmentEnd(ErrorContext::from_line_and_column(&self.line, column),
+                                            open_environment,
+                                            close_environment)
+    }
+
+    fn unexpected_environment_end(&self, environment_name: String, column: usize) -> FinlError {
+        FinlError::UnexpectedEnvironmentEnd(ErrorContext::from_line_and_column(&self.line, column), environment_name)
+    }
+
+    // column is that of the opening delimiter
+    fn unexpected_eol_while_parsing_command_arguments(&self, command_name: String, arg_number: usize, group_type: GroupType, column: usize) -> FinlError {
+        FinlError::UnexpectedEOLWhileParsingCommandArguments(ErrorContext::from_line_and_column(&self.line, column),
+                                                             command_name,
+                                                             arg_number,
+                                                             group_type)
+    }
+
+    fn text_parse(&mut self, command_context: CommandContext) {
+        let defining = matches!(command_context, CommandContext::UserCommandDefinition);
+        // Groups below this belong to our callers
+        let depth = self.stack.len();
+        if self.on_blank_line() && self.blank_line_parse() {
+            return;
+        }
+        if let Some((0, _)) = self.char_iterator.peek() {
+            // Skip leading whitespace at beginnings of lines
+            self.skip_spaces();
+        }
+        let mut start = self.get_column();
+        // Brackets inside an optional argument must balance. Brackets inside braces are not counted, so
+        // `\foo[{]}]` has an argument of `{]}`.
+        let mut bracket_depth = 0usize;
+        // A comment takes the line end with it
+        let mut comment = false;
+        loop {
+            match self.char_iterator.peek().cloned() {
+                None => {
+                    let space = !defining && !comment && self.line_end_is_space();
+                    self.push_eol_text_block(start, space);
+                    comment = false;
+                    if !self.next_line() {
+                        self.unclosed_braces(depth);
+                        return;
                     }
-                    else {
-                        self.push_error(self.unexpected_close_brace(top_of_stack.clone(), column));
-                        if let Some(group_type) = top_of_stack {
-                            self.stack.push(group_type);
-                        }
+                    if self.on_blank_line() && self.blank_line_parse() {
+                        return;
                     }
-                    self.char_iterator.next();
+                    self.skip_spaces();
                     start = self.get_column();
                 }
-                _ => {
-                    self.char_iterator.next();
+                Some((column, ch)) => {
+                    match ch {
+                        // The environment on top of the stack handles its own `\end`
+                        '\\' if self.at_environment_end(column)
+                            && matches!(self.open_group(), Some(GroupType::Environment(_))) => {
+                            self.push_text_block(start, column);
+                            return;
+                        }
+                        '\\' => {
+                            self.push_text_block(start, column);
+                            self.command_parse(command_context);
+                            // Arguments stop at a blank line
+                            if self.on_blank_line() && self.blank_line_parse() {
+                                return;
+                            }
+                            start = self.get_column();
+                        }
+                        // Macro definitions ignore spaces and use `~` for one
+                        _ if defining && ch.is_whitespace() => {
+                            self.push_text_block(start, column);
+                            self.char_iterator.next();
+                            start = self.get_column();
+                        }
+                        '~' if defining => {
+                            self.push_text_block(start, column);
+                            self.push_token(Token::ParsedText(Location::from_line_and_column(&self.line, column), " ".to_string()));
+                            self.char_iterator.next();
+                            start = self.get_column();
+                        }
+                        '#' if defining => {
+                            self.push_text_block(start, column);
+                            self.macro_parameter_parse(column);
+                            start = self.get_column();
+                        }
+                        // If we have a `%`, we dump whatever's left and ignore the rest of the line.
+                        '%' => {
+                            self.push_text_block(start, column);
+                            self.skip_to_end_of_line();
+                            comment = true;
+                            start = self.get_column();
+                        }
+                        '{' => {
+                            self.push_text_block(start, column);
+                            let location = Location::from_line_and_column(&self.line, column);
+                            self.push_token(Token::Bgroup(location.clone()));
+                            self.char_iterator.next();
+                            self.stack.push((GroupType::Brace, location));
+                            self.context.get_or_default::<FontStack>().begin_group();
+                            start = self.get_column();
+                        }
+                        '}' => {
+                            self.push_text_block(start, column);
+                            let top_of_stack = self.open_group().cloned();
+                            match top_of_stack {
+                                Some(GroupType::Brace) => {
+                                    self.stack.pop();
+                                    self.context.get_or_default::<FontStack>().end_group();
+                                    self.push_token(Token::Egroup(Location::from_line_and_column(&self.line, column)));
+                                }
+                                // The brace belongs to whoever pushed the argument, so we leave it for them.
+                                Some(GroupType::RequiredArgument) => {
+                                    return;
+                                }
+                                _ => {
+                                    self.push_error(self.unexpected_close_brace(top_of_stack, column));
+                                }
+                            }
+                            self.char_iterator.next();
+                            start = self.get_column();
+                        }
+                        '[' if self.open_group() == Some(&GroupType::OptionalArgument) => {
+                            bracket_depth += 1;
+                            self.char_iterator.next();
+                        }
+                        ']' if self.open_group() == Some(&GroupType::OptionalArgument) => {
+                            if bracket_depth == 0 {
+                                // As with a required argument, the caller consumes the bracket.
+                                self.push_text_block(start, column);
+                                return;
+                            }
+                            bracket_depth -= 1;
+                            self.char_iterator.next();
+                        }
+                        _ => {
+                            self.char_iterator.next();
+                        }
+                    }
                 }
             }
         }
-        self.push_eol_text_block(start);
+    }
+
+    // As in TeX, the end of a line is a space, except at the end of a paragraph or the input. A line ending in a
+    // control word doesn't get one, since the spaces after a command name are skipped.
+    fn line_end_is_space(&mut self) -> bool {
+        let contents = self.line.contents.trim_end();
+        let before_letters = contents.trim_end_matches(letter_test);
+        let backslashes = before_letters.len() - before_letters.trim_end_matches('\\').len();
+        !contents.is_empty() && backslashes.is_multiple_of(2)
+            && self.lines.peek().is_some_and(|line| !line.trim().is_empty())
+    }
+
+    fn open_group(&self) -> Option<&GroupType> {
+        self.stack.last().map(|(group, _)| group)
+    }
+
+    // Braces are closed in the text_parse that opened them, so that's where we report the ones the input ended in.
+    // Other groups are reported by whoever opened them.
+    fn unclosed_braces(&mut self, depth: usize) {
+        for (group, location) in self.stack.split_off(depth).into_iter().rev() {
+            self.context.get_or_default::<FontStack>().end_group();
+            self.push_error(self.unclosed_group(group, &location));
+        }
+    }
+
+    fn unclosed_group(&self, group_type: GroupType, location: &Location) -> FinlError {
+        FinlError::UnclosedGroup(self.error_context_at(location), group_type)
     }
 
     fn get_column(&mut self) -> usize {
         match self.char_iterator.peek() {
-            None => {
-                self.next_line();
-                0
+            None => self.line.contents.len(),
+            Some((column, _)) => *column,
+        }
+    }
+
+    // Skip white space without leaving the current line
+    fn skip_spaces(&mut self) {
+        while let Some((_, ch)) = self.char_iterator.peek() {
+            if ch.is_whitespace() {
+                self.char_iterator.next();
+            }
+            else {
+                break;
             }
-            Some((column, _)) => {
-                *column
+        }
+    }
+
+    fn skip_to_end_of_line(&mut self) {
+        while self.char_iterator.next().is_some() {}
+    }
+
+    fn advance(&mut self, char_count: usize) {
+        for _ in 0..char_count {
+            self.char_iterator.next();
+        }
+    }
+
+    // Consume a full extended grapheme cluster from the current line
+    fn next_grapheme(&mut self) -> Option<(usize, String)> {
+        let column = self.char_iterator.peek().map(|(column, _)| *column)?;
+        let grapheme = self.line.contents[column..].graphemes(true).next()?.to_string();
+        self.advance(grapheme.chars().count());
+        Some((column, grapheme))
+    }
+
+    fn at_environment_end(&self, column: usize) -> bool {
+        self.line.contents[column..].strip_prefix("\\end")
+            .is_some_and(|rest| !rest.starts_with(letter_test))
+    }
+
+    // Remove everything pushed to the output since `start`. Errors go back on the output so they still get
+    // reported, the tokens are returned so they can become a command argument.
+    fn take_tokens_from(&mut self, start: usize) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        for item in self.output.split_off(start) {
+            match item {
+                Ok(token) => tokens.push(token),
+                Err(error) => self.push_error(error),
             }
         }
+        tokens
     }
 
     fn command_parse(&mut self, command_context: CommandContext)  {
         let (command_start, _) = self.char_iterator.next().expect("This should not happen"); // get column of backslash
+        let location = Location::from_line_and_column(&self.line, command_start);
         let command_name = self.get_command_name(&command_context);
+        match command_name.as_str() {
+            "begin" => {
+                self.environment_parse(command_context, location);
+                return;
+            }
+            "newcommand" => {
+                self.macro_definition_parse(command_context, location);
+                return;
+            }
+            "end" => {
+                // An `\end` that closes an environment never gets here.
+                let name_column = self.get_column();
+                match self.environment_name_at("end", name_column) {
+                    Ok((name, length)) => {
+                        self.push_error(self.unexpected_environment_end(name, command_start));
+                        self.advance(length);
+                    }
+                    Err(err) => self.push_error(err),
+                }
+                return;
+            }
+            _ => {}
+        }
         match self.commands.get(&command_name).cloned() {
             None => {
                 self.push_error(self.undefined_command(command_name, command_start));
             }
             Some(command) => {
-                let mut args = Vec::with_capacity(command.parameters.len());
-                let mut parameter_number = 0;
-                for (format, ptype) in &command.parameters {
-                    parameter_number += 1;
-                    let possible_arg = match format {
-                        ParameterFormat::Star => Err(self.unimplemented(command_start)),
-                        ParameterFormat::Required =>
-                            self.parse_required_argument(&command.name, parameter_number, &command_context, *ptype),
-
-                        ParameterFormat::RequiredWithBraces => Err(self.unimplemented(command_start)),
-                        ParameterFormat::Optional => Err(self.unimplemented(command_start)),
-                        ParameterFormat::ArbitraryDelimiters => Err(self.unimplemented(command_start))
-                    };
-                    match possible_arg {
-                        Ok(token) => {
-                            args.push(token);
-                        }
-                        Err(err) => {
-                            self.push_error(err);
-                            return;
-                        }
+                let args = self.parse_arguments(&command.name, &command.parameters, &command_context, 0);
+                // Handlers and macros run when the macro being defined is used. They also don't get arguments
+                // which failed to parse.
+                if matches!(command_context, CommandContext::UserCommandDefinition) || has_erroneous_argument(&args) {
+                    self.push_token(Token::Command(location, command, args));
+                }
+                else {
+                    self.push_command(command, args, location);
+                }
+            }
+        }
+    }
+
+    // The first `skip` parameters have already been taken care of by the caller. If an argument fails, we report
+    // it and give it and the remaining arguments as `Token::ErroneousArgument`. Argument parsing only fails at a
+    // point where we can carry on with the rest of the input: a close brace or would-be argument that was left for
+    // the enclosing group, a blank line, or the end of the line or input.
+    fn parse_arguments(&mut self, name: &str, parameters: &[(ParameterFormat, ParameterType)], command_context: &CommandContext, skip: usize) -> Vec<Token> {
+        let mut args = Vec::with_capacity(parameters.len());
+        for (index, (format, ptype)) in parameters.iter().enumerate().skip(skip) {
+            let parameter_number = index + 1;
+            let arg = match format {
+                ParameterFormat::Star => Ok(self.parse_star()),
+                ParameterFormat::Required =>
+                    self.parse_required_argument(name, parameter_number, command_context, *ptype, false),
+                ParameterFormat::RequiredWithBraces =>
+                    self.parse_required_argument(name, parameter_number, command_context, *ptype, true),
+                ParameterFormat::Optional =>
+                    self.parse_optional_argument(name, parameter_number, command_context, *ptype, None),
+                ParameterFormat::OptionalWithDefault(default) =>
+                    self.parse_optional_argument(name, parameter_number, command_context, *ptype, Some(default)),
+                ParameterFormat::ArbitraryDelimiters =>
+                    self.parse_arbitrary_delimited_argument(name, parameter_number, *ptype)
+            };
+            match arg {
+                Ok(arg) => args.push(arg),
+                Err(error) => {
+                    self.push_error(error);
+                    let column = self.get_column();
+                    let location = Location::from_line_and_column(&self.line, column);
+                    args.resize(parameters.len() - skip, Token::ErroneousArgument(location));
+                    break;
+                }
+            }
+        }
+        args
+    }
+
+    fn environment_parse(&mut self, command_context: CommandContext, location: Location) {
+        let name_column = match self.skip_whitespace() {
+            SkipWhiteSpaceOutcome::Skipped => self.get_column(),
+            SkipWhiteSpaceOutcome::FoundBlankLine => {
+                self.push_error(self.blank_line_while_parsing_command_arguments("begin".to_string(), 1));
+                return;
+            }
+            SkipWhiteSpaceOutcome::EndOfFile => {
+                self.push_error(self.unexpected_eof_while_parsing_command_arguments("begin".to_string(), 1));
+                return;
+            }
+        };
+        let name = match self.environment_name_at("begin", name_column) {
+            Ok((name, length)) => {
+                self.advance(length);
+                name
+            }
+            Err(err) => {
+                self.push_error(err);
+                return;
+            }
+        };
+        // A star on the name is only allowed if the environment's first parameter is a star.
+        let starred = name.ends_with('*');
+        let environment = match self.environments.get(name.trim_end_matches('*')).cloned() {
+            Some(environment) if !starred || matches!(environment.args.first(), Some((ParameterFormat::Star, _))) =>
+                environment,
+            _ => {
+                self.push_error(self.undefined_environment(name, name_column));
+                return;
+            }
+        };
+        let mut args = Vec::with_capacity(environment.args.len());
+        let mut skip = 0;
+        if let Some((ParameterFormat::Star, _)) = environment.args.first() {
+            args.push(Token::Boolean(Location::from_line_and_column(&self.line, name_column), starred));
+            skip = 1;
+        }
+        args.append(&mut self.parse_arguments(&name, &environment.args, &command_context, skip));
+
+        self.stack.push((GroupType::Environment(environment.clone()), location.clone()));
+        let body = match environment.body_type {
+            ParameterType::VerbatimText => self.verbatim_body_parse(&name),
+            ParameterType::YAML => self.yaml_body_parse(&name),
+            body_type => {
+                if body_type != ParameterType::ParsedTokens {
+                    // For now, we parse the body anyway so we can find its end.
+                    let column = self.get_column();
+                    self.push_error(self.unimplemented(column));
+                }
+                let body_start = self.output.len();
+                self.text_parse(command_context);
+                self.take_tokens_from(body_start)
+            }
+        };
+        self.stack.pop();
+        // The body only stops early at `\end`. Otherwise, the input ended.
+        if self.char_iterator.peek().is_some() {
+            self.environment_end_parse(&name);
+        }
+        else {
+            self.push_error(self.unclosed_group(GroupType::Environment(environment.clone()), &location));
+        }
+        if matches!(command_context, CommandContext::UserCommandDefinition) || has_erroneous_argument(&args) {
+            // Handlers run when the macro is used and don't get arguments which failed to parse
+            self.push_token(Token::Environment(location, environment, args, body));
+        }
+        else {
+            self.push_environment(environment, args, body, location);
+        }
+    }
+
+    // Collect lines untouched up to a line starting with `\end{name}`, leaving us at the `\end`. Each line is
+    // paired with the column where its text starts, and the indentation of the `\end` line is returned as well.
+    fn raw_body_lines(&mut self, name: &str) -> (Vec<(Line, usize)>, String) {
+        let end_marker = format!("\\end{{{}}}", name);
+        let mut lines = Vec::new();
+        // Anything after `\begin` on its own line is part of the body.
+        let column = self.get_column();
+        if !self.line.contents[column..].trim().is_empty() {
+            lines.push((self.line.clone(), column));
+        }
+        while self.next_line() {
+            let text = self.line.contents.trim_start();
+            if text.starts_with(&end_marker) {
+                let indentation = self.line.contents[..self.line.contents.len() - text.len()].to_string();
+                self.advance(indentation.chars().count());
+                return (lines, indentation);
+            }
+            lines.push((self.line.clone(), 0));
+        }
+        (lines, String::new())
+    }
+
+    // Lines of a raw body have the indentation of the `\end` line stripped. Blank lines may be less indented.
+    // Lines which aren't indented enough are reported and dropped.
+    fn dedented_body_lines(&mut self, name: &str) -> Vec<(Line, usize)> {
+        let (lines, indentation) = self.raw_body_lines(name);
+        let mut dedented = Vec::with_capacity(lines.len());
+        for (line, start) in lines {
+            match dedent(&line.contents, start, &indentation) {
+                Some(column) => dedented.push((line, column)),
+                None => {
+                    let column = line.contents.len() - line.contents.trim_start().len();
+                    self.push_error(FinlError::InsufficientIndentationInEnvironment(
+                        ErrorContext::from_line_and_column(&line, column), name.to_string()));
+                }
+            }
+        }
+        dedented
+    }
+
+    fn verbatim_body_parse(&mut self, name: &str) -> Vec<Token> {
+        self.dedented_body_lines(name).into_iter()
+            .map(|(line, column)| Token::RawText(Location::from_line_and_column(&line, column),
+                                                 line.contents[column..].to_string()))
+            .collect()
+    }
+
+    // The body is a single YAML document. Errors are reported against the lines of the body in the source.
+    fn yaml_body_parse(&mut self, name: &str) -> Vec<Token> {
+        let lines = self.dedented_body_lines(name);
+        let end_column = self.get_column();
+        let location = match lines.first() {
+            Some((line, column)) => Location::from_line_and_column(line, *column),
+            None => Location::from_line_and_column(&self.line, end_column),
+        };
+        let text = lines.iter()
+            .map(|(line, column)| &line.contents[*column..])
+            .collect::<Vec<&str>>()
+            .join("\n");
+        match YamlLoader::load_from_str(&text) {
+            Ok(mut documents) => {
+                let yaml = if documents.is_empty() { Yaml::Null } else { documents.swap_remove(0) };
+                vec![Token::YAML(location, yaml)]
+            }
+            Err(error) => {
+                // The marker's line starts at 1
+                let marker = error.marker();
+                let context = match lines.get(marker.line().saturating_sub(1)).or(lines.last()) {
+                    Some((line, start)) => {
+                        ErrorContext::from_line_and_column(line, start + marker_column(marker, &line.contents[*start..]))
+                    }
+                    None => ErrorContext::from_line_and_column(&self.line, 0),
+                };
+                self.push_error(FinlError::InvalidYAML(context, yaml_error_message(&error)));
+                Vec::new()
+            }
+        }
+    }
+
+    // Read `{name}` on the current line at `column` without consuming anything. Returns the name and the number of
+    // chars it takes up. A name that isn't closed on the line takes the rest of it, which is skipped so that it
+    // isn't parsed again as an unclosed group.
+    fn environment_name_at(&mut self, command: &str, column: usize) -> Result<(String, usize), FinlError> {
+        let text = &self.line.contents[column..];
+        if !text.starts_with('{') {
+            return Err(self.missing_braces_for_command_argument(command.to_string(), 1, column));
+        }
+        match text.find('}') {
+            Some(end) => {
+                let name = text[1..end].to_string();
+                let length = name.chars().count() + 2;
+                Ok((name, length))
+            }
+            None => {
+                let error = self.unexpected_eol_while_parsing_command_arguments(command.to_string(), 1,
+                                                                                GroupType::RequiredArgument, column);
+                self.skip_to_end_of_line();
+                Err(error)
+            }
+        }
+    }
+
+    // We're at the `\end` closing the environment `name`
+    fn environment_end_parse(&mut self, name: &str) {
+        let column = self.get_column();
+        let after_end = &self.line.contents[column + "\\end".len()..];
+        let name_start = after_end.trim_start();
+        let spaces = after_end[..after_end.len() - name_start.len()].chars().count();
+        let name_column = self.line.contents.len() - name_start.len();
+        match self.environment_name_at("end", name_column) {
+            Ok((end_name, length)) => {
+                if end_name != name {
+                    self.push_error(self.mismatched_environment_end(name.to_string(), end_name.clone(), column));
+                    // If the `\end` belongs to an enclosing environment, we leave it for that environment.
+                    let base_name = end_name.trim_end_matches('*');
+                    if self.stack.iter().any(|(group, _)| matches!(group, GroupType::Environment(environment) if environment.name == base_name)) {
+                        return;
                     }
                 }
-                self.push_command(command.clone(), args, command_start);
+                self.advance("\\end".len() + spaces + length);
+            }
+            Err(err) => {
+                self.push_error(err);
+                self.advance("\\end".len() + spaces);
             }
         }
     }
 
     fn get_command_name(&mut self, command_context: &CommandContext) -> String {
-        let name_start = self.char_iterator.peek();
-        match name_start {
+        // `_` is a letter only inside macro definitions
+        let is_letter = |ch: char| letter_test(ch)
+            || (ch == '_' && matches!(command_context, CommandContext::UserCommandDefinition));
+        match self.char_iterator.peek().cloned() {
             None => " ".to_string(), // backslash at end of line ≡ \␣
             Some((_, ch)) => {
-                if letter_test(*ch) {
+                if is_letter(ch) {
                     let mut command_name = String::new();
                     // consume letters:
-                    loop {
-                        if let Some((_, ch)) = self.char_iterator.peek() {
-                            if letter_test(*ch) {
-                                command_name.push(*ch);
-                                self.char_iterator.next();
-                            }
-                            else {
-                                // consume white space immediately after the command name
-                                while let Some((_, ch)) = self.char_iterator.peek() {
-                                    if ch.is_whitespace() {
-                                        self.char_iterator.next();
-                                    }
-                                    else {
-                                        break;
-                                    }
-                                }
-                                break;
-                            }
-                        }
-                        else {
-                            // We reached the end of the line
-                            break;
-                        }
-                    }
-
-                    // Skip any trailing whitespace:
                     while let Some((_, ch)) = self.char_iterator.peek() {
-                        if ch.is_whitespace() {
+                        if is_letter(*ch) {
+                            command_name.push(*ch);
                             self.char_iterator.next();
                         }
                         else {
                             break;
                         }
                     }
+                    // consume white space immediately after the command name
+                    self.skip_spaces();
                     command_name
                 }
                 else {
-                    // TODO: This doesn't correctly handle characters like 🇨🇦 or 🐻‍❄️
-                    ch.to_string()
+                    // A command symbol is a whole grapheme, so, e.g., 🇨🇦 and 🐻‍❄️ are single symbols
+                    let (_, symbol) = self.next_grapheme().expect("There is at least one character");
+                    symbol
                 }
             }
         }
     }
 
-    fn parse_required_argument(&mut self, command: &String, parameter_number: usize, command_context: &CommandContext, ptype: ParameterType) -> Result<Token,FinlError> {
-        // white space before a required argument is ignored.
-        match self.skip_whitespace() {
-            SkipWhiteSpaceOutcome::Skipped => {}
-            SkipWhiteSpaceOutcome::FoundBlankLine => {
-                return Err(self.blank_line_while_parsing_command_arguments(command.clone(), parameter_number));
-            }
+    // Parse tokens up to the character closing `group_type`. Returns None if a blank line or the end of input came
+    // first.
+    // Like a brace group, the argument is a scope for font declarations
+    fn parse_delimited_tokens(&mut self, group_type: GroupType, location: &Location, closing: char, command_context: CommandContext) -> Option<Vec<Token>> {
+        self.stack.push((group_type, location.clone()));
+        self.context.get_or_default::<FontStack>().begin_group();
+        let tokens_start = self.output.len();
+        self.text_parse(command_context);
+        self.context.get_or_default::<FontStack>().end_group();
+        self.stack.pop();
+        let tokens = self.take_tokens_from(tokens_start);
+        // text_parse only stops early at the closing delimiter
+        match self.char_iterator.next() {
+            Some((_, ch)) if ch == closing => Some(tokens),
+            _ => None
+        }
+    }
+
+    // A star must come immediately after the command name (or the spaces following it). Absence is `false`.
+    fn parse_star(&mut self) -> Token {
+        let column = self.get_column();
+        let location = Location::from_line_and_column(&self.line, column);
+        if let Some((_, '*')) = self.char_iterator.peek() {
+            self.char_iterator.next();
+            Token::Boolean(location, true)
+        }
+        else {
+            Token::Boolean(location, false)
+        }
+    }
+
+    // An optional argument must start on the same line as the command. If it's omitted, we parse the default
+    // instead, or give an empty token list if there is no default.
+    fn parse_optional_argument(&mut self, command: &str, parameter_number: usize, command_context: &CommandContext, ptype: ParameterType, default: Option<&String>) -> Result<Token, FinlError> {
+        // The spaces before an omitted argument stay in the text
+        let column = self.get_column();
+        if self.line.contents[column..].trim_start().starts_with('[') {
+            self.skip_spaces();
+        }
+        let column = self.get_column();
+        let location = Location::from_line_and_column(&self.line, column);
+        if ptype != ParameterType::ParsedTokens && ptype != ParameterType::KeyValueList {
+            return Err(self.unimplemented(column));
+        }
+        let arg = if let Some((_, '[')) = self.char_iterator.peek() {
+            match command_context {
+                CommandContext::Text | CommandContext::UserCommandDefinition => {
+                    self.char_iterator.next();
+                    match self.parse_delimited_tokens(GroupType::OptionalArgument, &location, ']', *command_context) {
+                        Some(arg) => Ok(Token::Tokens(location, arg)),
+                        None => Err(self.unclosed_argument(command, parameter_number, &location))
+                    }
+                }
+                CommandContext::Math => Err(self.unimplemented(column)),
+            }
+        }
+        else {
+            let mut arg = Vec::new();
+            // A default can use its own command, so it's limited like a macro expansion
+            if let Some(default) = default.filter(|_| self.begin_expansion(command, &location, 0)) {
+                let default = default.clone();
+                self.context.get_or_default::<FontStack>().begin_group();
+                let output = self.parse_default(&default);
+                self.context.get_or_default::<FontStack>().end_group();
+                self.expansion.chain.pop();
+                for item in output {
+                    match item {
+                        Ok(mut token) => {
+                            token.relocate(&location);
+                            arg.push(token);
+                        }
+                        Err(mut error) => {
+                            let context = error.context_mut();
+                            context.location = location.clone();
+                            context.line_contents = self.line.original_contents().to_string();
+                            // The expansions inside the default aren't in the document either
+                            context.expanded_from.clear();
+                            self.push_error(error);
+                        }
+                    }
+                }
+            }
+            Ok(Token::Tokens(location, arg))
+        };
+        match arg {
+            Ok(token) if ptype == ParameterType::KeyValueList => Ok(self.key_value_list_from_argument(token)),
+            arg => arg
+        }
+    }
+
+    // The default is parsed with the same definitions and handler state as the document, which are lent to the
+    // parser for it. Since the default isn't in the document, what comes of it is placed where the argument would be.
+    fn parse_default(&mut self, default: &str) -> Vec<Result<Token, FinlError>> {
+        let mut parser = Parser::from_string(default);
+        parser.commands = mem::take(&mut self.commands);
+        parser.environments = mem::take(&mut self.environments);
+        parser.methods = mem::take(&mut self.methods);
+        parser.context = mem::take(&mut self.context);
+        parser.expansion = mem::take(&mut self.expansion);
+        let output = parser.parse();
+        self.commands = mem::take(&mut parser.commands);
+        self.environments = mem::take(&mut parser.environments);
+        self.methods = mem::take(&mut parser.methods);
+        self.context = mem::take(&mut parser.context);
+        self.expansion = mem::take(&mut parser.expansion);
+        output
+    }
+
+    // The argument is delimited either by braces or by the first grapheme after the command and its next
+    // occurrence. It may not cross lines and is passed on as raw text.
+    fn parse_arbitrary_delimited_argument(&mut self, command: &str, parameter_number: usize, ptype: ParameterType) -> Result<Token, FinlError> {
+        self.skip_spaces();
+        let (column, delimiter) = match self.next_grapheme() {
+            Some(delimiter) => delimiter,
+            None => {
+                let column = self.get_column();
+                return Err(FinlError::MissingArgumentDelimiter(ErrorContext::from_line_and_column(&self.line, column),
+                                                               command.to_string(), parameter_number));
+            }
+        };
+        if ptype != ParameterType::VerbatimText {
+            return Err(self.unimplemented(column));
+        }
+        let location = Location::from_line_and_column(&self.line, column);
+        let closing = if delimiter == "{" { "}".to_string() } else { delimiter.clone() };
+        self.stack.push((GroupType::ArbitraryDelim(delimiter.clone()), location.clone()));
+        let text_start = self.get_column();
+        // Braces must balance when they're the delimiters
+        let mut brace_depth = 0usize;
+        let result = loop {
+            match self.next_grapheme() {
+                None => {
+                    break Err(self.unexpected_eol_while_parsing_command_arguments(command.to_string(), parameter_number,
+                                                                                 GroupType::ArbitraryDelim(delimiter), column));
+                }
+                Some((end, grapheme)) => {
+                    if grapheme == closing {
+                        if brace_depth == 0 {
+                            break Ok(Token::RawText(location, self.line.contents[text_start..end].to_string()));
+                        }
+                        brace_depth -= 1;
+                    }
+                    else if delimiter == "{" && grapheme == "{" {
+                        brace_depth += 1;
+                    }
+                }
+            }
+        };
+        self.stack.pop();
+        result
+    }
+
+    fn parse_required_argument(&mut self, command: &str, parameter_number: usize, command_context: &CommandContext, ptype: ParameterType, braces_required: bool) -> Result<Token,FinlError> {
+        // white space before a required argument is ignored.
+        match self.skip_whitespace() {
+            SkipWhiteSpaceOutcome::Skipped => {}
+            SkipWhiteSpaceOutcome::FoundBlankLine => {
+                return Err(self.blank_line_while_parsing_command_arguments(command.to_string(), parameter_number));
+            }
             SkipWhiteSpaceOutcome::EndOfFile => {
-                return Err(self.unexpected_eof_while_parsing_command_arguments(command.clone(), parameter_number));
+                return Err(self.unexpected_eof_while_parsing_command_arguments(command.to_string(), parameter_number));
             }
         }
         // Check next character. We know there is one from skipping whitespace.
-        let (loc, ch) = self.char_iterator.peek().cloned().unwrap();
-        match ch {
+        let (column, ch) = self.char_iterator.peek().cloned().unwrap();
+        if ptype != ParameterType::ParsedTokens && ptype != ParameterType::KeyValueList
+            && ptype != ParameterType::MacroDefinition {
+            return Err(self.unimplemented(column));
+        }
+        if braces_required && ch != '{' {
+            // We leave the would-be argument in place to be parsed as ordinary input.
+            return Err(self.missing_braces_for_command_argument(command.to_string(), parameter_number, column));
+        }
+        let arg = match ch {
             '{' => {
-                self.stack.push(GroupType::RequiredArgument);
-                self.char_iterator.next();
+                let location = Location::from_line_and_column(&self.line, column);
                 match command_context {
-                    CommandContext::Text => {
-                        let tokens_end = self.output.len();
-                        self.text_parse();
-                        let arg  = self.output.split_off(tokens_end);
-                        return Err(self.unimplemented(loc));
-                        // return arg.iter()
-                        //     .map(|v| Token::Tokens(Location::from_line_and_column(&self.line, *loc), v))
-                        // ;
-                    }
-                    CommandContext::UserCommandDefinition => {
-                        return Err(self.unimplemented(loc));
-                    }
-                    CommandContext::Math => {
-                        return Err(self.unimplemented(loc));
+                    CommandContext::Text | CommandContext::UserCommandDefinition => {
+                        self.char_iterator.next();
+                        match self.parse_delimited_tokens(GroupType::RequiredArgument, &location, '}', *command_context) {
+                            Some(arg) => Ok(Token::Tokens(location, arg)),
+                            None => Err(self.unclosed_argument(command, parameter_number, &location))
+                        }
                     }
+                    CommandContext::Math => Err(self.unimplemented(column)),
                 }
-
-                // parse to close brace
             },
             '}' => {
-                // Oops this brace does not belong
+                // Oops this brace does not belong. We leave it for the enclosing group.
+                Err(self.close_brace_while_parsing_command_arguments(command.to_string(), parameter_number, column))
             },
+            // Likewise, the `\end` of the enclosing environment is left for it
+            '\\' if self.at_environment_end(column) && matches!(self.open_group(), Some(GroupType::Environment(_))) => {
+                Err(self.environment_end_while_parsing_command_arguments(command.to_string(), parameter_number, column))
+            }
             '\\' => {
-                // We have a command
+                // A single command is the argument. If the command failed, its error is the last thing output.
+                // Anything else it outputs is either what it stands for or errors from inside its own arguments,
+                // which stay on the output.
+                let location = Location::from_line_and_column(&self.line, column);
+                let tokens_start = self.output.len();
+                self.command_parse(*command_context);
+                if let Some(Err(_)) = self.output.last() {
+                    return self.output.pop().unwrap();
+                }
+                let mut tokens = self.take_tokens_from(tokens_start);
+                if tokens.len() == 1 {
+                    Ok(tokens.remove(0))
+                }
+                else {
+                    // A command with a handler can stand for any number of tokens
+                    Ok(Token::Tokens(location, tokens))
+                }
             }
-            _ => {}
+            // In a definition, `#n` is a single token
+            '#' if matches!(command_context, CommandContext::UserCommandDefinition) => {
+                self.macro_parameter_parse(column);
+                self.output.pop().expect("A parameter or an error was output")
+            }
+            _ => {
+                let (column, grapheme) = self.next_grapheme().unwrap();
+                Ok(Token::ParsedText(Location::from_line_and_column(&self.line, column), grapheme))
+            }
+        };
+        match arg {
+            Ok(token) if ptype == ParameterType::KeyValueList => Ok(self.key_value_list_from_argument(token)),
+            arg => arg
         }
-        // If open brace parse to close brace
-        // Otherwise grab next token.
-
-        return Err(self.unimplemented(loc))
     }
+}
 
+// Returns the column where the text of a body line starts once `indentation` is removed, or None if the line isn't
+// indented enough. A line that doesn't start at column 0 is the remainder of the `\begin` line and is left alone.
+fn dedent(contents: &str, start: usize, indentation: &str) -> Option<usize> {
+    if start > 0 {
+        Some(start)
+    }
+    else if contents.starts_with(indentation) {
+        Some(indentation.len())
+    }
+    else if contents.trim().is_empty() {
+        Some(contents.len())
+    }
+    else {
+        None
+    }
+}
 
+fn has_erroneous_argument(args: &[Token]) -> bool {
+    args.iter().any(|arg| matches!(arg, Token::ErroneousArgument(_)))
+}
 
-
-
+// Runs of spaces and tabs in text are a single space. Other white space, such as U+00A0 NO-BREAK SPACE, is kept.
+fn collapse_spaces(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    for ch in text.chars() {
+        if ch != ' ' && ch != '\t' {
+            collapsed.push(ch);
+        }
+        else if !collapsed.ends_with(' ') {
+            collapsed.push(' ');
+        }
+    }
+    collapsed
 }
 
 fn letter_test(ch: char) -> bool {
@@ -417,9 +1225,12 @@ fn letter_test(ch: char) -> bool {
 #[cfg(test)]
 mod test {
 
-    use std::assert_matches::assert_matches;
+    use std::assert_matches;
 
     use super::*;
+    use crate::diagnostics::{Diagnostic, Severity};
+    use crate::implementation::{ExpandedArgument, Implementation};
+    use crate::variables::{Variable, VariableStore};
 
     // macro_rules! match_error {
     //     ($e:expr => UndefinedCommand) => {
@@ -442,7 +1253,7 @@ mod test {
         let first_item = output.remove(0);
         let error = first_item.expect_err("First item should be an error");
 
-        assert_matches!(error, FinlError::UndefinedCommand(_, cmd_name) if cmd_name == "undefined".to_string());
+        assert_matches!(error, FinlError::UndefinedCommand(_, cmd_name, _) if cmd_name == "undefined");
 
     }
 
@@ -458,10 +1269,10 @@ mod test {
         let command = item1.expect("First token should not be an error");
         assert_matches!(command,
             Token::Command(_, command, args)
-                if command.name == "foo".to_string() && args.len() == 0
+                if command.name == "foo" && args.is_empty()
         );
         let text = item2.expect("Second token should not be an error");
-        assert_matches!(text, Token::ParsedText(_, text) if text == "a".to_string());
+        assert_matches!(text, Token::ParsedText(_, text) if text == "a");
 
     }
 
@@ -481,15 +1292,17 @@ mod test {
         let open = output.remove(0);
         assert_matches!(open.unwrap(), Token::Bgroup(_));
         let contents = output.remove(0);
-        assert_matches!(contents.unwrap(), Token::ParsedText(_, "n".to_string()));
+        assert_matches!(contents.unwrap(), Token::ParsedText(_, text) if text == "n");
         let close = output.remove(0);
         assert_matches!(close.unwrap(), Token::Egroup(_));
 
-        let mut parser = Parser::from_string("{\n}"); // Really? I want to ignore a blank line after an opening brace?
+        let mut parser = Parser::from_string("{\n}"); // The line end is a space, as in TeX
         let mut output = parser.parse();
-        assert_eq!(output.len(), 2);
+        assert_eq!(output.len(), 3);
         let open = output.remove(0);
         assert_matches!(open.unwrap(), Token::Bgroup(_));
+        let contents = output.remove(0);
+        assert_matches!(contents.unwrap(), Token::ParsedText(_, text) if text == " ");
         let close = output.remove(0);
         assert_matches!(close.unwrap(), Token::Egroup(_));
 
@@ -501,111 +1314,975 @@ mod test {
         let close = output.remove(0);
         assert_matches!(close.unwrap(), Token::Egroup(_));
         let err = output.remove(0);
-        assert_matches!(err.unwrap_err(), FinlError::UnexpectedCloseBrace(_, group_type) if group_type == None);
+        assert_matches!(err.unwrap_err(), FinlError::UnexpectedCloseBrace(_, group_type) if group_type.is_none());
     }
 
 
-    /*
+    #[test]
+    fn braces_tokenize_correctly_with_text() {
+        let mut parser = Parser::from_string("{abc}");
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 3);
+        assert_matches!(output.remove(0).unwrap(), Token::Bgroup(_));
+        assert_matches!(output.remove(0).unwrap(), Token::ParsedText(_, text) if text == "abc");
+        assert_matches!(output.remove(0).unwrap(), Token::Egroup(_));
+    }
 
-#[test]
-fn braces_tokenize_correctly_with_text() {
-    let mut context = Parser::from_string("{abc}");
-    let mut output = context.parse();
-    println!("{:?}", output);
-    assert_eq!(output.len(), 3);
-    let (item, _loc) = output.remove(0);
-    assert_eq!(Ok(Token::Bgroup), item);
-    let (item, _loc) = output.remove(0);
-    assert_eq!(Ok(Token::ParsedText("abc".to_string())), item);
-    let (item, _loc) = output.remove(0);
-    assert_eq!(Ok(Token::Egroup), item);
+    #[test]
+    fn skip_white_space_finds_blank_line() {
+        let mut parser = Parser::from_string("\n\na");
+        assert_eq!(parser.skip_whitespace(), SkipWhiteSpaceOutcome::FoundBlankLine);
+    }
 
-}
+    #[test]
+    fn blank_line_before_argument_is_error() {
+        let mut parser = Parser::from_string("\\foo\n\n{a}");
+        parser.define_command("foo", vec![(ParameterFormat::Required, ParameterType::ParsedTokens)]);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 6);
+        let error = output.remove(0).expect_err("First item should be an error");
+        assert_matches!(error,
+            FinlError::BlankLineWhileParsingCommandArguments(_, command_name, 1) if command_name == "foo");
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::ErroneousArgument(_)]));
+        // The blank line still ends the paragraph
+        assert_matches!(output.remove(0).unwrap(), Token::ParagraphBreak(location) if location.line_number == 2);
+    }
 
-#[test]
-fn skip_white_space_finds_blank_line() {
-    let mut context = Parser::from_string("\n\na");
-    assert_eq!(context.skip_whitespace(), SkipWhiteSpaceOutcome::FoundBlankLine);
-}
+    #[test]
+    fn can_parse_a_command_with_single_required_argument_parsed_text() {
+        let mut parser = Parser::from_string("\\foo{a} \\foo b \\foo\\foo{c}");
+        parser.define_command("foo", vec![(ParameterFormat::Required, ParameterType::ParsedTokens)]);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 5);
+
+        let first = output.remove(0).expect("Expected \\foo{a}");
+        if let Token::Command(_, command, args) = first {
+            assert_eq!(command.name, "foo");
+            assert_eq!(args.len(), 1);
+            assert_matches!(&args[0], Token::Tokens(_, tokens)
+                if matches!(tokens.as_slice(), [Token::ParsedText(_, text)] if text == "a"));
+        }
+        else {
+            panic!("Expected \\foo");
+        }
 
-#[test]
-fn blank_line_before_argument_is_error() {
-    let mut parser = Parser::from_string("\\foo\n\n{a}");
-    parser.define_command("foo", vec![(ParameterFormat::Required, ParameterType::ParsedTokens)]);
-    let mut output = parser.parse();
-    println!("{:?}", output);
-    assert_eq!(output.len(), 4);
-    let (item, loc) = output.remove(0);
-    if let FinlError::BlankLineWhileParsingCommandArguments(command_name, parameter_number) = item.unwrap_err() {
-        assert_eq!("foo", command_name);
-        assert_eq!(1, parameter_number);
+        assert_matches!(output.remove(0).unwrap(), Token::ParsedText(_, text) if text == " ");
+
+        let second = output.remove(0).expect("Expected \\foo b");
+        if let Token::Command(_, command, args) = second {
+            assert_eq!(command.name, "foo");
+            assert_eq!(args.len(), 1);
+            assert_matches!(&args[0], Token::ParsedText(_, text) if text == "b");
+        }
+        else {
+            panic!("Expected \\foo");
+        }
+
+        assert_matches!(output.remove(0).unwrap(), Token::ParsedText(_, text) if text == " ");
+
+        let third = output.remove(0).expect("Expected \\foo\\foo{c}");
+        if let Token::Command(_, command, args) = third {
+            assert_eq!(command.name, "foo");
+            assert_eq!(args.len(), 1);
+            if let Token::Command(_, inner_command, inner_args) = &args[0] {
+                assert_eq!(inner_command.name, "foo");
+                assert_eq!(inner_args.len(), 1);
+                assert_matches!(&inner_args[0], Token::Tokens(_, tokens)
+                    if matches!(tokens.as_slice(), [Token::ParsedText(_, text)] if text == "c"));
+            }
+            else {
+                panic!("First argument to {} was {}", command.name, args[0]);
+            }
+        }
+        else {
+            panic!("Expected \\foo");
+        }
     }
-    else {
-        panic!("I thought this would be an error");
+
+    #[test]
+    fn single_grapheme_required_argument() {
+        let mut parser = Parser::from_string("\\foo 🇨🇦x");
+        parser.define_command("foo", vec![(ParameterFormat::Required, ParameterType::ParsedTokens)]);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 2);
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::ParsedText(_, text)] if text == "🇨🇦"));
+        assert_matches!(output.remove(0).unwrap(), Token::ParsedText(_, text) if text == "x");
+    }
+
+    #[test]
+    fn required_argument_can_span_lines() {
+        let mut parser = Parser::from_string("\\foo{a\n{b}}c");
+        parser.define_command("foo", vec![(ParameterFormat::Required, ParameterType::ParsedTokens)]);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 2);
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::Tokens(_, tokens)] if tokens.len() == 4));
+        assert_matches!(output.remove(0).unwrap(), Token::ParsedText(_, text) if text == "c");
+    }
+
+    #[test]
+    fn unterminated_required_argument_is_error() {
+        let mut parser = Parser::from_string("\\foo{a");
+        parser.define_command("foo", vec![(ParameterFormat::Required, ParameterType::ParsedTokens)]);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 2);
+        assert_matches!(output.remove(0).unwrap_err(),
+            FinlError::UnexpectedEOFWhileParsingCommandArguments(_, command_name, 1) if command_name == "foo");
+    }
+
+    #[test]
+    fn star_is_a_boolean_argument() {
+        let mut parser = Parser::from_string("\\section*{a}\\section{b}\\section *{c}");
+        parser.define_command("section", vec![(ParameterFormat::Star, ParameterType::Boolean),
+                                              (ParameterFormat::Required, ParameterType::ParsedTokens)]);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 3);
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::Boolean(_, true), Token::Tokens(..)]));
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::Boolean(_, false), Token::Tokens(..)]));
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::Boolean(_, true), Token::Tokens(..)]));
+    }
+
+    #[test]
+    fn star_does_not_look_past_end_of_line() {
+        let mut parser = Parser::from_string("\\foo\n*");
+        parser.define_command("foo", vec![(ParameterFormat::Star, ParameterType::Boolean)]);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 2);
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::Boolean(_, false)]));
+        assert_matches!(output.remove(0).unwrap(), Token::ParsedText(_, text) if text == "*");
+    }
+
+    #[test]
+    fn can_parse_optional_arguments() {
+        let mut parser = Parser::from_string("\\foo[a[b]c]\\foo{x}\\foo [{]}]");
+        parser.define_command("foo", vec![(ParameterFormat::Optional, ParameterType::ParsedTokens)]);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 6);
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::Tokens(_, tokens)]
+                if matches!(tokens.as_slice(), [Token::ParsedText(_, text)] if text == "a[b]c")));
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::Tokens(_, tokens)] if tokens.is_empty()));
+        assert_matches!(output.remove(0).unwrap(), Token::Bgroup(_));
+        assert_matches!(output.remove(0).unwrap(), Token::ParsedText(_, text) if text == "x");
+        assert_matches!(output.remove(0).unwrap(), Token::Egroup(_));
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::Tokens(_, tokens)]
+                if matches!(tokens.as_slice(), [Token::Bgroup(_), Token::ParsedText(_, text), Token::Egroup(_)]
+                    if text == "]")));
+
+        // An omitted optional argument leaves the space before it alone
+        let mut parser = Parser::from_string("\\bar{a} b");
+        parser.define_command("bar", vec![(ParameterFormat::Required, ParameterType::ParsedTokens),
+                                          (ParameterFormat::Optional, ParameterType::ParsedTokens)]);
+        let output = parser.parse();
+        assert_eq!(output.len(), 2);
+        assert_matches!(&output[1], Ok(Token::ParsedText(_, text)) if text == " b");
+    }
+
+    #[test]
+    fn omitted_optional_argument_gets_default() {
+        let mut parser = Parser::from_string("\\foo\\foo[b]");
+        parser.define_command("bar", Vec::default());
+        parser.define_command("foo", vec![(ParameterFormat::OptionalWithDefault("a\\bar".to_string()),
+                                           ParameterType::ParsedTokens)]);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 2);
+        // The default's tokens are where the argument would be
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::Tokens(_, tokens)]
+                if matches!(tokens.as_slice(), [Token::ParsedText(text_location, text), Token::Command(bar_location, bar, _)]
+                    if text == "a" && bar.name == "bar" && text_location.column == 4 && bar_location.column == 4)));
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::Tokens(_, tokens)]
+                if matches!(tokens.as_slice(), [Token::ParsedText(_, text)] if text == "b")));
+    }
+
+    #[test]
+    fn unterminated_optional_argument_is_error() {
+        let mut parser = Parser::from_string("\\foo[a[b]");
+        parser.define_command("foo", vec![(ParameterFormat::Optional, ParameterType::ParsedTokens)]);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 2);
+        assert_matches!(output.remove(0).unwrap_err(),
+            FinlError::UnexpectedEOFWhileParsingCommandArguments(_, command_name, 1) if command_name == "foo");
+    }
+
+    #[test]
+    fn can_parse_arbitrarily_delimited_arguments() {
+        let mut parser = Parser::from_string("\\verb|a%b\\c|\\verb {x{y}}\\verb🇨🇦|🇨🇦");
+        parser.define_command("verb", vec![(ParameterFormat::ArbitraryDelimiters, ParameterType::VerbatimText)]);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 3);
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::RawText(_, text)] if text == "a%b\\c"));
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::RawText(_, text)] if text == "x{y}"));
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::RawText(_, text)] if text == "|"));
+    }
+
+    #[test]
+    fn arbitrarily_delimited_argument_cannot_cross_lines() {
+        let mut parser = Parser::from_string("\\verb|abc\nd|");
+        parser.define_command("verb", vec![(ParameterFormat::ArbitraryDelimiters, ParameterType::VerbatimText)]);
+        let mut output = parser.parse();
+        assert_matches!(output.remove(0).unwrap_err(),
+            FinlError::UnexpectedEOLWhileParsingCommandArguments(context, command_name, 1, GroupType::ArbitraryDelim(delimiter))
+                if command_name == "verb" && delimiter == "|" && context.location.column == 5);
+
+        let mut parser = Parser::from_string("\\verb  \nabc");
+        parser.define_command("verb", vec![(ParameterFormat::ArbitraryDelimiters, ParameterType::VerbatimText)]);
+        let output = parser.parse();
+        let error = output[0].as_ref().unwrap_err();
+        assert_matches!(error, FinlError::MissingArgumentDelimiter(context, command_name, 1)
+            if command_name == "verb" && context.location.column == 7);
+        assert_eq!(error.to_string(), "line ended before the delimiter of argument 1 of `\\verb`");
     }
-}
 
-#[test]
-fn can_parse_a_command_with_single_required_argument_parsed_text() {
-    let mut parser = Parser::from_string("\\foo{a} \\foo b \\foo\\foo{c}");
-    parser.define_command("foo", vec![(ParameterFormat::Required, ParameterType::ParsedTokens)]);
-    let mut output = parser.parse();
-    println!("{:?}", output);
-    assert_eq!(output.len(), 3);
-    let (item1, loc1) = output.remove(0);
-    let (item2, loc2) = output.remove(0);
-    let (item3, loc3) = output.remove(0);
-    if let Token::Command(command, args) = item1.unwrap() {
-        // first command
-        assert_eq!(command.name, "foo".to_string());
-        assert_eq!(args.len(), 1);
-        if let Token::ParsedText(text) = &args[0] {
-            assert_eq!("a", text);
+    #[test]
+    fn braces_can_be_mandatory() {
+        let mut parser = Parser::from_string("\\url{a}\\url b");
+        parser.define_command("url", vec![(ParameterFormat::RequiredWithBraces, ParameterType::ParsedTokens)]);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 4);
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::Tokens(_, tokens)]
+                if matches!(tokens.as_slice(), [Token::ParsedText(_, text)] if text == "a")));
+        assert_matches!(output.remove(0).unwrap_err(),
+            FinlError::MissingBracesForCommandArgument(context, command_name, 1)
+                if command_name == "url" && context.location.column == 12);
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::ErroneousArgument(_)]));
+        assert_matches!(output.remove(0).unwrap(), Token::ParsedText(_, text) if text == "b");
+    }
+
+    #[test]
+    fn can_parse_an_environment() {
+        let mut parser = Parser::from_string("\\begin{foo}{x}\n  a \\bar\n\\end{foo}b");
+        parser.define_command("bar", Vec::default());
+        parser.define_environment("foo", vec![(ParameterFormat::Required, ParameterType::ParsedTokens)],
+                                  ParameterType::ParsedTokens);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 2);
+        let environment = output.remove(0).expect("Expected the environment");
+        if let Token::Environment(location, environment, args, body) = environment {
+            assert_eq!(location.line_number, 1);
+            assert_eq!(environment.name, "foo");
+            assert_matches!(args.as_slice(), [Token::Tokens(_, tokens)]
+                if matches!(tokens.as_slice(), [Token::ParsedText(_, text)] if text == "x"));
+            assert_matches!(body.as_slice(), [Token::ParsedText(_, text), Token::Command(_, bar, _)]
+                if text == "a " && bar.name == "bar");
         }
         else {
-            panic!("First argument to {} was {}", command.name, args[0]);
+            panic!("Expected an environment");
         }
+        assert_matches!(output.remove(0).unwrap(), Token::ParsedText(_, text) if text == "b");
     }
-    else {
-        panic!("Expected \\foo");
+
+    #[test]
+    fn a_missing_argument_leaves_the_environment_end() {
+        let mut parser = Parser::from_string("\\begin{foo}\\bar\\end{foo}after");
+        parser.define_command("bar", vec![(ParameterFormat::Required, ParameterType::ParsedTokens)]);
+        parser.define_environment("foo", Vec::default(), ParameterType::ParsedTokens);
+        let output = parser.parse();
+        assert_eq!(output.len(), 3);
+        assert_matches!(&output[0], Err(FinlError::EnvironmentEndWhileParsingCommandArguments(context, command, 1))
+            if command == "bar" && context.location.column == 15);
+        assert_matches!(&output[1], Ok(Token::Environment(_, _, _, body))
+            if matches!(body.as_slice(), [Token::Command(_, bar, args)] if bar.name == "bar"
+                && matches!(args.as_slice(), [Token::ErroneousArgument(..)])));
+        assert_matches!(&output[2], Ok(Token::ParsedText(_, text)) if text == "after");
+    }
+
+    #[test]
+    fn an_unclosed_environment_name_takes_the_rest_of_the_line() {
+        let mut parser = Parser::from_string("\\begin{x y\nnext \\end{z");
+        let output = parser.parse();
+        assert_eq!(output.len(), 3);
+        assert_matches!(&output[0], Err(FinlError::UnexpectedEOLWhileParsingCommandArguments(context, command, 1, _))
+            if command == "begin" && context.location.column == 6);
+        assert_matches!(&output[1], Ok(Token::ParsedText(_, text)) if text == "next ");
+        assert_matches!(&output[2], Err(FinlError::UnexpectedEOLWhileParsingCommandArguments(_, command, 1, _))
+            if command == "end");
     }
 
-    if let Token::Command(command, args) = item2.unwrap() {
-        // second command
-        assert_eq!(command.name, "foo".to_string());
-        assert_eq!(args.len(), 1);
-        if let Token::ParsedText(text) = &args[0] {
-            assert_eq!("b", text);
+    #[test]
+    fn environments_can_have_a_star_form() {
+        let mut parser = Parser::from_string("\\begin{foo*}a\\end{foo*}\\begin{foo}b\\end{foo}\\begin{bar*}\\end{bar*}");
+        parser.define_environment("foo", vec![(ParameterFormat::Star, ParameterType::Boolean)],
+                                  ParameterType::ParsedTokens);
+        parser.define_environment("bar", Vec::default(), ParameterType::ParsedTokens);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 4);
+        assert_matches!(output.remove(0).unwrap(), Token::Environment(_, _, args, _)
+            if matches!(args.as_slice(), [Token::Boolean(_, true)]));
+        assert_matches!(output.remove(0).unwrap(), Token::Environment(_, _, args, _)
+            if matches!(args.as_slice(), [Token::Boolean(_, false)]));
+        assert_matches!(output.remove(0).unwrap_err(), FinlError::UndefinedEnvironment(_, name, _) if name == "bar*");
+        assert_matches!(output.remove(0).unwrap_err(), FinlError::UnexpectedEnvironmentEnd(_, name) if name == "bar*");
+    }
+
+    #[test]
+    fn mismatched_environment_end_is_error() {
+        let mut parser = Parser::from_string("\\begin{foo}\\begin{bar}\\end{foo}\\end{baz}");
+        parser.define_environment("foo", Vec::default(), ParameterType::ParsedTokens);
+        parser.define_environment("bar", Vec::default(), ParameterType::ParsedTokens);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 3);
+        assert_matches!(output.remove(0).unwrap_err(), FinlError::MismatchedEnvironmentEnd(context, open, close)
+            if open == "bar" && close == "foo" && context.location.column == 22);
+        assert_matches!(output.remove(0).unwrap(), Token::Environment(_, foo, _, body)
+            if foo.name == "foo" && matches!(body.as_slice(), [Token::Environment(_, bar, _, _)] if bar.name == "bar"));
+        assert_matches!(output.remove(0).unwrap_err(), FinlError::UnexpectedEnvironmentEnd(_, name) if name == "baz");
+
+        let mut parser = Parser::from_string("\\begin{foo}\\end{baz}");
+        parser.define_environment("foo", Vec::default(), ParameterType::ParsedTokens);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 2);
+        assert_matches!(output.remove(0).unwrap_err(), FinlError::MismatchedEnvironmentEnd(_, open, close)
+            if open == "foo" && close == "baz");
+        assert_matches!(output.remove(0).unwrap(), Token::Environment(..));
+    }
+
+    #[test]
+    fn verbatim_environment_strips_indentation() {
+        let mut parser = Parser::from_string(
+            "  \\begin{verbatim}\n    {a} % \\b\n\n      c\n    \\end{foo}\n  \\end{verbatim}d");
+        parser.define_environment("verbatim", Vec::default(), ParameterType::VerbatimText);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 2);
+        let environment = output.remove(0).expect("Expected the environment");
+        if let Token::Environment(_, _, _, body) = environment {
+            let lines: Vec<String> = body.iter().map(|token| token.to_string()).collect();
+            assert_eq!(lines, vec!["  {a} % \\b", "", "    c", "  \\end{foo}"]);
+            assert_matches!(&body[0], Token::RawText(location, _) if location.line_number == 2 && location.column == 2);
         }
         else {
-            panic!("First argument to {} was {}", command.name, args[0]);
+            panic!("Expected an environment");
         }
+        assert_matches!(output.remove(0).unwrap(), Token::ParsedText(_, text) if text == "d");
     }
-    else {
-        panic!("Expected \\foo");
+
+    #[test]
+    fn verbatim_lines_must_be_indented_as_much_as_the_end() {
+        let mut parser = Parser::from_string("\\begin{verbatim}\n  a\n b\n  \\end{verbatim}");
+        parser.define_environment("verbatim", Vec::default(), ParameterType::VerbatimText);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 2);
+        assert_matches!(output.remove(0).unwrap_err(), FinlError::InsufficientIndentationInEnvironment(context, name)
+            if name == "verbatim" && context.location.line_number == 3 && context.location.column == 1);
+        assert_matches!(output.remove(0).unwrap(), Token::Environment(_, _, _, body)
+            if matches!(body.as_slice(), [Token::RawText(_, text)] if text == "a"));
     }
 
-    if let Token::Command(command, args) = item3.unwrap() {
-        // third command
-        assert_eq!(command.name, "foo".to_string());
-        assert_eq!(args.len(), 1);
-        if let Token::Command(inner_command, inner_args) = &args[0] {
-            assert_eq!(inner_command.name, "foo".to_string());
-            assert_eq!(inner_args.len(), 1);
-            if let Token::ParsedText(text) = &inner_args[0] {
-                assert_eq!("a", text);
+    #[test]
+    fn yaml_environment_is_parsed() {
+        let mut parser = Parser::from_string("\\begin{data}\n    title: \\foo\n    list:\n      - 1\n      - two\n    \\end{data}");
+        parser.define_environment("data", Vec::default(), ParameterType::YAML);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 1);
+        let environment = output.remove(0).expect("Expected the environment");
+        if let Token::Environment(_, _, _, body) = environment {
+            assert_eq!(body.len(), 1);
+            if let Token::YAML(location, yaml) = &body[0] {
+                assert_eq!(location.line_number, 2);
+                assert_eq!(yaml["title"].as_str(), Some("\\foo"));
+                assert_eq!(yaml["list"][0].as_i64(), Some(1));
+                assert_eq!(yaml["list"][1].as_str(), Some("two"));
             }
             else {
-                panic!("First argument to {} was {}", command.name, args[0]);
-            }            }
+                panic!("Expected YAML");
+            }
+        }
         else {
-            panic!("First argument to {} was {}", command.name, args[0]);
+            panic!("Expected an environment");
         }
     }
-    else {
-        panic!("Expected \\foo");
+
+    #[test]
+    fn yaml_errors_point_into_the_document() {
+        let mut parser = Parser::from_string("text\n\\begin{data}\n  a: 1\n  b: [1, 2\n  \\end{data}");
+        parser.define_environment("data", Vec::default(), ParameterType::YAML);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 3);
+        assert_matches!(output.remove(0).unwrap(), Token::ParsedText(..));
+        assert_matches!(output.remove(0).unwrap_err(), FinlError::InvalidYAML(context, _)
+            if context.location.line_number == 4 && context.location.column == 2);
+        assert_matches!(output.remove(0).unwrap(), Token::Environment(_, _, _, body) if body.is_empty());
+    }
+
+    #[test]
+    fn can_parse_key_value_lists() {
+        let mut parser = Parser::from_string("\\foo{ foo = { e } , bar =   kappa , baz=3, qux={a}{b}, quux = x=\\bar, }");
+        parser.define_command("bar", Vec::default());
+        parser.define_command("foo", vec![(ParameterFormat::Required, ParameterType::KeyValueList)]);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 1);
+        let command = output.remove(0).expect("Expected \\foo");
+        if let Token::Command(_, _, mut args) = command {
+            if let Token::KeyValueList(_, list) = args.remove(0) {
+                let keys: Vec<&String> = list.keys().collect();
+                assert_eq!(keys, vec!["foo", "bar", "baz", "qux", "quux"]);
+                assert_matches!(list["foo"].as_slice(), [Token::ParsedText(_, text)] if text == " e ");
+                assert_matches!(list["bar"].as_slice(), [Token::ParsedText(_, text)] if text == "kappa");
+                assert_matches!(list["baz"].as_slice(), [Token::ParsedText(_, text)] if text == "3");
+                assert_eq!(list["qux"].len(), 6);
+                assert_matches!(list["quux"].as_slice(), [Token::ParsedText(_, text), Token::Command(_, bar, _)]
+                    if text == "x=" && bar.name == "bar");
+            }
+            else {
+                panic!("Expected a key-value list");
+            }
+        }
+        else {
+            panic!("Expected \\foo");
+        }
+    }
+
+    #[test]
+    fn key_value_list_errors() {
+        let mut parser = Parser::from_string("\\foo[1a=b, a=c, a=d, e, f=\\undefined]");
+        parser.define_command("foo", vec![(ParameterFormat::Optional, ParameterType::KeyValueList)]);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 5);
+        assert_matches!(output.remove(0).unwrap_err(), FinlError::UndefinedCommand(_, name, _) if name == "undefined");
+        assert_matches!(output.remove(0).unwrap_err(), FinlError::InvalidKey(context, key)
+            if key == "1a" && context.location.column == 5);
+        assert_matches!(output.remove(0).unwrap_err(), FinlError::DuplicateKey(context, key)
+            if key == "a" && context.location.column == 16);
+        assert_matches!(output.remove(0).unwrap_err(), FinlError::MissingKeyValueSeparator(_, key) if key == "e");
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::KeyValueList(_, list)]
+                if list.len() == 2 && list["a"][0].to_string() == "c" && list["f"].is_empty()));
+
+        // Columns are for the text as written, before é is decomposed
+        let mut parser = Parser::from_string("\\foo[\u{e9}=1, 1x=2]");
+        parser.define_command("foo", vec![(ParameterFormat::Optional, ParameterType::KeyValueList)]);
+        assert_matches!(&parser.parse()[0], Err(FinlError::InvalidKey(context, key))
+            if key == "1x" && context.location.column == 11);
+
+        // The text has runs of spaces collapsed, but the columns are still those in the line
+        let mut parser = Parser::from_string("\\foo{a=1,    1x=2, \t b  =3,\t\t}");
+        parser.define_command("foo", vec![(ParameterFormat::Required, ParameterType::KeyValueList)]);
+        let output = parser.parse();
+        assert_matches!(&output[0], Err(FinlError::InvalidKey(context, key)) if key == "1x" && context.location.column == 13);
+        assert_matches!(&output[1], Ok(Token::Command(_, _, args))
+            if matches!(args.as_slice(), [Token::KeyValueList(_, list)]
+                if matches!(list["b"].as_slice(), [Token::ParsedText(location, text)] if text == "3" && location.column == 25)));
     }
-}
 
- */
-}
\ No newline at end of file
+    #[test]
+    fn can_load_definitions_from_yaml() {
+        let mut parser = Parser::from_string("\\textbf{a}\\begin{code}[x]\n  b\n  \\end{code}");
+        parser.define_from_yaml("commands.yml", include_str!("../resources/commands.yml"))
+            .expect("commands.yml should load");
+        parser.define_from_yaml("environments.yml", "environments:\n  - environment: code\n    params:\n      - format: optional\n        default: text\n    body: verbatim\n")
+            .expect("environments should load");
+        assert_eq!(parser.commands["textit"].parameters,
+                   vec![(ParameterFormat::Required, ParameterType::ParsedTokens)]);
+        assert_eq!(parser.environments["code"].args,
+                   vec![(ParameterFormat::OptionalWithDefault("text".to_string()), ParameterType::ParsedTokens)]);
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 2);
+        assert_matches!(output.remove(0).unwrap(), Token::Font(_, font, _) if font.axis == "series" && font.value == "b");
+        assert_matches!(output.remove(0).unwrap(), Token::Environment(_, _, _, body)
+            if matches!(body.as_slice(), [Token::RawText(_, text)] if text == "b"));
+    }
+
+    #[test]
+    fn definition_errors_point_at_the_yaml() {
+        let mut parser = Parser::from_string("");
+        let errors = parser.define_from_yaml("bad.yml", "commands:\n  - command: good\n  - command: bad\n    params:\n      - format: requird\n        type: parsed\n      - format: required\n        type: parsd\n")
+            .expect_err("Definitions should fail");
+        assert_eq!(errors.len(), 2);
+        assert_matches!(&errors[0], FinlError::UnknownParameterFormat(context, name)
+            if name == "requird" && context.location.file == "bad.yml" && context.location.line_number == 5
+                && context.location.column == 16 && context.line_contents == "      - format: requird");
+        assert_matches!(&errors[1], FinlError::UnknownParameterType(context, name)
+            if name == "parsd" && context.location.line_number == 8 && context.location.column == 14);
+        assert!(parser.commands.contains_key("good"));
+        assert!(!parser.commands.contains_key("bad"));
+
+        let errors = parser.define_from_yaml("bad.yml", "commands:\n  - name: foo\n")
+            .expect_err("Definitions should fail");
+        assert_matches!(&errors[0], FinlError::InvalidDefinition(context, _) if context.location.line_number == 2);
+
+        let errors = parser.define_from_yaml("bad.yml", "variables:\n  a: &x b\n  c: *x\n")
+            .expect_err("Aliases should fail");
+        assert_eq!(errors.len(), 1);
+        assert_matches!(&errors[0], FinlError::InvalidDefinition(context, expected)
+            if context.location.line_number == 3 && context.location.column == 5 && expected.contains("alias"));
+    }
+
+    #[test]
+    fn handlers_replace_commands_and_environments() {
+        #[derive(Default)]
+        struct Count(usize);
+
+        let mut parser = Parser::from_string("\\count{a}\\count{b}\\fail\\begin{wrap}x\\end{wrap}");
+        parser.define_command_with_handler("count", vec![(ParameterFormat::Required, ParameterType::ParsedTokens)],
+            |context: &mut ExecutionContext, location: &Location, args: Vec<Token>| {
+                context.get_or_default::<Count>().0 += 1;
+                Ok(vec![Token::ParsedText(location.clone(), format!("{}{}", args[0], context.get::<Count>().unwrap().0))])
+            });
+        parser.define_command_with_handler("fail", Vec::default(),
+            |_: &mut ExecutionContext, location: &Location, _: Vec<Token>| {
+                Err(FinlError::Unimplemented(ErrorContext { location: location.clone(), line_contents: String::new(), expanded_from: Vec::new() }))
+            });
+        parser.define_environment_with_handler("wrap", Vec::default(), ParameterType::ParsedTokens,
+            |_: &mut ExecutionContext, location: &Location, _: Vec<Token>, mut body: Vec<Token>| {
+                body.insert(0, Token::Bgroup(location.clone()));
+                body.push(Token::Egroup(location.clone()));
+                Ok(body)
+            });
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 6);
+        assert_matches!(output.remove(0).unwrap(), Token::ParsedText(_, text) if text == "[[a]]1");
+        assert_matches!(output.remove(0).unwrap(), Token::ParsedText(_, text) if text == "[[b]]2");
+        assert_matches!(output.remove(0).unwrap_err(), FinlError::Unimplemented(context) if context.location.column == 18);
+        assert_matches!(output.remove(0).unwrap(), Token::Bgroup(_));
+        assert_matches!(output.remove(0).unwrap(), Token::ParsedText(_, text) if text == "x");
+        assert_matches!(output.remove(0).unwrap(), Token::Egroup(_));
+        assert_eq!(parser.context().get::<Count>().unwrap().0, 2);
+
+        let mut parser = Parser::from_string("\\foo\\nothing\\foo\\fail");
+        parser.define_command("foo", vec![(ParameterFormat::Required, ParameterType::ParsedTokens)]);
+        parser.define_command_with_handler("nothing", Vec::default(),
+            |_: &mut ExecutionContext, _: &Location, _: Vec<Token>| Ok(Vec::new()));
+        parser.define_command_with_handler("fail", Vec::default(),
+            |_: &mut ExecutionContext, location: &Location, _: Vec<Token>| {
+                Err(FinlError::Unimplemented(ErrorContext { location: location.clone(), line_contents: String::new(), expanded_from: Vec::new() }))
+            });
+        let mut output = parser.parse();
+        assert_eq!(output.len(), 3);
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::Tokens(_, tokens)] if tokens.is_empty()));
+        // The second \\foo is left with a placeholder for the failed argument
+        assert_matches!(output.remove(0).unwrap_err(), FinlError::Unimplemented(_));
+        assert_matches!(output.remove(0).unwrap(), Token::Command(_, _, args)
+            if matches!(args.as_slice(), [Token::ErroneousArgument(_)]));
+    }
+
+    #[test]
+    fn variables_are_hierarchical() {
+        let mut variables = VariableStore::default();
+        variables.set("font.series.bold", "b");
+        variables.set("font.series.medium", "m");
+        assert_eq!(variables.get_value("font.series.bold"), Some("b"));
+        assert_eq!(variables.get_value("font.series"), None);
+        assert_matches!(variables.get("font.series"), Some(Variable::Table(_)));
+        assert_eq!(variables.get_value("font.shape"), None);
+        variables.set("font.series", "x");
+        assert_eq!(variables.get_value("font.series"), Some("x"));
+        assert_eq!(variables.get_value("font.series.bold"), None);
+    }
+
+    #[test]
+    fn implementation_arguments_are_expanded() {
+        let mut parser = Parser::from_string("\\textbf{a}");
+        parser.define_from_yaml("commands.yml", include_str!("../resources/commands.yml")).unwrap();
+        let command = parser.commands["textbf"].clone();
+        let location = Location::default();
+        let args = vec![Token::Tokens(location.clone(), vec![Token::ParsedText(location.clone(), "a".to_string())])];
+        let implementation = command.implementation.as_ref().expect("\\textbf has an implementation");
+        assert_eq!(implementation.method, "internal:font");
+        let error_context = ErrorContext { location, line_contents: String::new(), expanded_from: Vec::new() };
+        let expanded = implementation.expand("textbf", &parser.context().variables, &args, &error_context).unwrap();
+        assert_matches!(expanded.as_slice(), [ExpandedArgument::Text(axis), ExpandedArgument::Text(value),
+                                             ExpandedArgument::Argument(Token::Tokens(..))]
+            if axis == "series" && value == "b");
+
+        let implementation = Implementation::new("m", vec!["$$1 costs ${price} x$2".to_string()]);
+        let mut variables = VariableStore::default();
+        variables.set("price", "3");
+        let args = vec![Token::ParsedText(Location::default(), "a".to_string())];
+        assert_eq!(implementation.expand("foo", &variables, &args, &error_context),
+                   Err(FinlError::ArgumentOutOfRange(error_context.clone(), "foo".to_string(), 2)));
+        let args = vec![args[0].clone(), Token::ParsedText(Location::default(), "b".to_string())];
+        assert_eq!(implementation.expand("foo", &variables, &args, &error_context),
+                   Ok(vec![ExpandedArgument::Text("$1 costs 3 xb".to_string())]));
+        // A braced argument gives the text inside it, without any braces
+        let implementation = Implementation::new("m", vec!["pre-$1".to_string()]);
+        let args = vec![Token::Tokens(Location::default(), vec![
+            Token::ParsedText(Location::default(), "a".to_string()), Token::Bgroup(Location::default()),
+            Token::ParsedText(Location::default(), "bc".to_string()), Token::Egroup(Location::default())])];
+        assert_eq!(implementation.expand("foo", &variables, &args, &error_context),
+                   Ok(vec![ExpandedArgument::Text("pre-abc".to_string())]));
+        let implementation = Implementation::new("m", vec!["${font.shape.italic}".to_string()]);
+        assert_eq!(implementation.expand("foo", &variables, &args, &error_context),
+                   Err(FinlError::UndefinedVariable(error_context.clone(), "foo".to_string(), "font.shape.italic".to_string())));
+    }
+
+    #[test]
+    fn font_commands_annotate_their_text() {
+        let mut parser = Parser::from_string("\\textbf{a \\textit{b}} {\\upshape\\textbf{c}} \\textit{d} \\nosuch{e}");
+        parser.define_from_yaml("commands.yml", include_str!("../resources/commands.yml")).unwrap();
+        // A declaration with no text
+        parser.commands.insert("upshape".to_string(), Rc::new(Command::with_implementation("upshape", vec![],
+            Implementation::new("internal:font", vec!["shape".to_string(), "n".to_string()]))));
+        parser.commands.insert("nosuch".to_string(), Rc::new(Command::with_implementation("nosuch",
+            vec![(ParameterFormat::Required, ParameterType::ParsedTokens)], Implementation::new("internal:nosuch", vec![]))));
+        let output = parser.parse();
+        let attributes = |token: &Token| match token {
+            Token::Font(_, font, _) => font.attributes.iter().map(|(axis, value)| format!("{}={}", axis, value))
+                .collect::<Vec<_>>().join(","),
+            _ => panic!("Expected a font token, got {:?}", token)
+        };
+        assert_eq!(output.len(), 9);
+        let bold = output[0].as_ref().unwrap();
+        assert_eq!(attributes(bold), "series=b");
+        assert_matches!(bold, Token::Font(_, _, text) if text.len() == 2 && attributes(&text[1]) == "series=b,shape=it");
+        assert_eq!(attributes(output[3].as_ref().unwrap()), "series=b,shape=n");
+        // The declaration ended with its group
+        assert_eq!(attributes(output[6].as_ref().unwrap()), "shape=it");
+        assert_matches!(&output[8], Err(FinlError::UndefinedMethod(_, command, method))
+            if command == "nosuch" && method == "internal:nosuch");
+
+        let mut parser = Parser::from_string("\\textsl{f}");
+        parser.define_from_yaml("commands.yml", include_str!("../resources/commands.yml")).unwrap();
+        assert_eq!(attributes(parser.parse()[0].as_ref().unwrap()), "shape=sl");
+
+        // A declaration in an argument ends with it, in a macro too
+        let mut parser = Parser::from_string("\\textit{x}\\textbf{\\upshape a}\\textbf{b}\\newcommand\\up[required]{\\textbf{\\upshape#1}\\textbf{#1}}\\up{c}");
+        parser.define_from_yaml("commands.yml", include_str!("../resources/commands.yml")).unwrap();
+        parser.commands.insert("upshape".to_string(), Rc::new(Command::with_implementation("upshape", vec![],
+            Implementation::new("internal:font", vec!["shape".to_string(), "n".to_string()]))));
+        let output = parser.parse();
+        assert_eq!(output.len(), 5);
+        for bold in &output[1..] {
+            assert_eq!(attributes(bold.as_ref().unwrap()), "series=b");
+        }
+    }
+
+    #[test]
+    fn can_define_and_use_macros() {
+        let mut parser = Parser::from_string("\\newcommand\\my_x{x}\\newcommand{\\greet}[required, optional=World]{Hello, ~#2 ~ \\foo{#1} \\my_x}\n\\greet{a}[b] \\greet{c}");
+        parser.define_command("foo", vec![(ParameterFormat::Required, ParameterType::ParsedTokens)]);
+        let output: Vec<Token> = parser.parse().into_iter().map(Result::unwrap).collect();
+        let text: String = output.iter().map(Token::to_string).collect();
+        assert_eq!(text, "Hello, b \\foox Hello, World \\foox");
+        assert_matches!(&output[4], Token::Command(_, command, args)
+            if command.name == "foo" && args[0].to_string() == "[[a]]");
+        assert_eq!(parser.commands["greet"].parameters,
+                   vec![(ParameterFormat::Required, ParameterType::ParsedTokens),
+                        (ParameterFormat::OptionalWithDefault("World".to_string()), ParameterType::ParsedTokens)]);
+    }
+
+    #[test]
+    fn parameters_can_be_single_token_arguments() {
+        let mut parser = Parser::from_string("\\newcommand\\a[required]{\\foo#1}\\a{xy}");
+        parser.define_command("foo", vec![(ParameterFormat::Required, ParameterType::ParsedTokens)]);
+        let output = parser.parse();
+        assert_eq!(output.len(), 1);
+        assert_matches!(&output[0], Ok(Token::Command(_, foo, args)) if foo.name == "foo"
+            && matches!(args.as_slice(), [Token::Tokens(_, tokens)]
+                if matches!(tokens.as_slice(), [Token::ParsedText(_, text)] if text == "xy")));
+    }
+
+    #[test]
+    fn macros_only_use_commands_defined_before_them() {
+        let mut parser = Parser::from_string("\\newcommand\\a{\\b}\\newcommand\\b{x}\\a");
+        let output = parser.parse();
+        assert_eq!(output.len(), 1);
+        assert_matches!(&output[0], Err(FinlError::UndefinedCommand(context, name, _))
+            if name == "b" && context.location.column == 14);
+    }
+
+    #[test]
+    fn definitions_in_macros_are_made_when_the_macro_is_expanded() {
+        let mut parser = Parser::from_string("\\newcommand\\a[required]{\\newcommand\\b{#1!}}\\b\\a{x}\\b\\a{y}");
+        let output = parser.parse();
+        assert_eq!(output.len(), 4);
+        assert_matches!(&output[0], Err(FinlError::UndefinedCommand(_, name, _)) if name == "b");
+        assert_matches!(&output[1], Ok(Token::ParsedText(_, text)) if text == "x");
+        assert_matches!(&output[2], Ok(Token::ParsedText(_, text)) if text == "!");
+        assert_matches!(&output[3], Err(FinlError::CommandAlreadyDefined(context, name))
+            if name == "b" && context.location.column == 24);
+    }
+
+    #[test]
+    fn macro_definition_errors() {
+        let mut parser = Parser::from_string("\\newcommand\\a[required]{#2}\\newcommand\\b{}\\newcommand\\b{}\\newcommand\\c[squiggly]{}\\my_x");
+        let output = parser.parse();
+        assert_eq!(output.len(), 5);
+        assert_matches!(&output[0], Err(FinlError::ArgumentOutOfRange(_, name, 2)) if name == "a");
+        assert_matches!(&output[1], Err(FinlError::CommandAlreadyDefined(_, name)) if name == "b");
+        assert_matches!(&output[2], Err(FinlError::UnknownParameterFormat(context, name))
+            if name == "squiggly" && context.location.column == 71);
+        // `_` is only a letter inside a macro definition
+        assert_matches!(&output[3], Err(FinlError::UndefinedCommand(_, name, _)) if name == "my");
+    }
+
+    #[test]
+    fn macros_expand_inside_arguments() {
+        let mut parser = Parser::from_string("\\newcommand\\twice[required]{#1#1}\\newcommand\\wrap[required]{\\textbf{\\twice{#1}}}\\wrap{z}");
+        parser.define_from_yaml("commands.yml", include_str!("../resources/commands.yml")).unwrap();
+        let output = parser.parse();
+        assert_eq!(output.len(), 1);
+        assert_matches!(output[0].as_ref().unwrap(), Token::Font(_, font, text)
+            if font.value == "b" && matches!(text.as_slice(), [Token::ParsedText(_, a), Token::ParsedText(_, b)] if a == "z" && b == "z"));
+    }
+
+    #[test]
+    fn macro_expansion_is_limited() {
+        let mut parser = Parser::from_string("\\newcommand\\r{x\\r}\\r");
+        parser.set_max_expansion_depth(5);
+        let output = parser.parse();
+        assert_eq!(output.len(), 6);
+        assert_matches!(&output[5], Err(FinlError::ExpansionTooDeep(_, chain)) if chain.len() == 6);
+
+        let mut parser = Parser::from_string("\\newcommand\\c{y}\\newcommand\\b{\\c\\c}\\newcommand\\a{\\b\\b}\\a\\a");
+        parser.set_max_expansions(4);
+        let output = parser.parse();
+        // The second `\\a` starts a new count
+        assert_eq!(output.len(), 6);
+        assert_matches!(&output[2], Err(FinlError::TooManyExpansions(_, chain)) if chain == &["a", "b"]);
+
+        // A macro that doubles its argument runs out of tokens long before it gets too deep
+        let mut parser = Parser::from_string("\\newcommand\\r[required]{\\r{#1#1}}\\r{x}");
+        let output = parser.parse();
+        assert_eq!(output.len(), 1);
+        assert_matches!(&output[0], Err(FinlError::TooManyExpansions(_, chain)) if chain.len() < 20);
+
+        // A default that uses its own command
+        let mut parser = Parser::from_string("\\newcommand\\a[optional=\\a]{x}\\a");
+        let output = parser.parse();
+        assert_matches!(output.last(), Some(Ok(Token::ParsedText(_, text))) if text == "x");
+        assert_matches!(output.iter().find(|item| item.is_err()), Some(Err(FinlError::ExpansionTooDeep(context, chain)))
+            if chain.len() == 101 && context.location.column == 31 && context.expanded_from.is_empty());
+
+        let mut parser = Parser::from_string("\\foo");
+        parser.define_command("foo", vec![(ParameterFormat::OptionalWithDefault("\\foo".to_string()), ParameterType::ParsedTokens)]);
+        let output = parser.parse();
+        assert_matches!(output.iter().find(|item| item.is_err()), Some(Err(FinlError::ExpansionTooDeep(_, chain)))
+            if chain.len() == 101 && chain.iter().all(|name| name == "foo"));
+    }
+
+    #[test]
+    fn errors_in_macros_carry_the_expansion_chain() {
+        let mut parser = Parser::from_string("\\newcommand\\inner{\n  \\textit{a}}\n\\newcommand\\outer{\\inner}\nx \\outer");
+        parser.define_from_yaml("commands.yml", "commands:\n  - command: textit\n    params:\n      - format: required\n    implementation:\n      method: internal:font\n      arguments: [shape, '${nothing}', $1]\n").unwrap();
+        let output = parser.parse();
+        assert_eq!(output.len(), 2);
+        let context = match &output[1] {
+            Err(FinlError::UndefinedVariable(context, command, variable)) if command == "textit" && variable == "nothing" => context,
+            error => panic!("Expected an undefined variable, got {:?}", error)
+        };
+        let position = |location: &Location| (location.line_number, location.column);
+        assert_eq!(position(&context.location), (2, 2));
+        assert_eq!(context.expanded_from.iter().map(position).collect::<Vec<_>>(), vec![(3, 18), (4, 2)]);
+    }
+
+    #[test]
+    fn command_symbols_are_graphemes() {
+        // A flag, a ZWJ sequence with a variation selector and an emoji with a skin-tone modifier
+        let symbols = ["🇨🇦", "🐻\u{200d}❄\u{fe0f}", "👋🏽"];
+        let input = symbols.iter().map(|symbol| format!("\\{}x", symbol)).collect::<String>();
+        let mut parser = Parser::from_string(&input);
+        for symbol in symbols {
+            parser.define_command(symbol, vec![]);
+        }
+        let output = parser.parse();
+        assert_eq!(output.len(), 6);
+        for (index, symbol) in symbols.iter().enumerate() {
+            assert_matches!(&output[index * 2], Ok(Token::Command(_, command, _)) if command.name == *symbol);
+            assert_matches!(&output[index * 2 + 1], Ok(Token::ParsedText(_, text)) if text == "x");
+        }
+
+        // An undefined symbol is reported whole
+        let mut parser = Parser::from_string("\\🇫🇷");
+        assert_matches!(parser.parse().as_slice(), [Err(FinlError::UndefinedCommand(_, name, _))] if name == "🇫🇷");
+    }
+
+    #[test]
+    fn input_is_normalized() {
+        // The precomposed é becomes e + U+0301, which is a letter for command names, and ṩ has its marks reordered
+        let input = "ṩ \\caf\u{e9} x\\undefined";
+        let mut parser = Parser::from_string(input);
+        parser.define_command("cafe\u{301}", vec![]);
+        let output = parser.parse();
+        assert_eq!(output.len(), 4);
+        assert_matches!(&output[0], Ok(Token::ParsedText(_, text)) if text == "s\u{323}\u{307} ");
+        assert_matches!(&output[1], Ok(Token::Command(location, command, _))
+            if command.name == "cafe\u{301}" && location.column == 4);
+        // Columns are for the original text
+        assert_matches!(&output[2], Ok(Token::ParsedText(location, text)) if text == "x" && location.column == 11);
+        assert_matches!(&output[3], Err(FinlError::UndefinedCommand(context, _, _))
+            if context.location.column == 12 && context.line_contents == input);
+
+        let mut parser = Parser::with_options(input, ParserOptions { normalize: false });
+        parser.define_command("cafe\u{301}", vec![]);
+        assert_matches!(&parser.parse()[1], Err(FinlError::UndefinedCommand(_, name, _)) if name == "caf\u{e9}");
+    }
+
+    #[test]
+    fn errors_render_with_the_source_line() {
+        let mut parser = Parser::from_string("é\t\\foo bar");
+        let output = parser.parse();
+        let error = output[1].as_ref().unwrap_err();
+        assert_eq!(error.to_string(), "undefined command `\\foo`");
+        assert_eq!(error.render(), "error[F0001]: undefined command `\\foo`\n --> STRING CONSTANT:1:3\n  |\n1 | é\t\\foo bar\n  |  \t^^^^\n  = help: commands are defined with `\\newcommand` or in a definition file\n");
+
+        // The line of a macro definition is gone by the time it's expanded
+        let mut parser = Parser::from_string("\\newcommand\\inner{\\textit{a}}\n\\newcommand\\outer{\\inner}\nx \\outer");
+        parser.define_from_yaml("commands.yml", "commands:\n  - command: textit\n    params:\n      - format: required\n    implementation:\n      method: internal:font\n      arguments: [shape, '${nothing}', $1]\n").unwrap();
+        let output = parser.parse();
+        assert_eq!(output[1].as_ref().unwrap_err().render(),
+                   "error[F0020]: undefined variable `nothing` in the implementation of `\\textit`\n --> STRING CONSTANT:1:19\n  = note: macro used here: STRING CONSTANT:2:19\n  = note: macro used here: STRING CONSTANT:3:3\n");
+
+        // At the end of the input, errors point at the end of the last line
+        let mut parser = Parser::from_string("line one\n\\foo");
+        parser.define_command("foo", vec![(ParameterFormat::Required, ParameterType::ParsedTokens)]);
+        let output = parser.parse();
+        assert_eq!(output[1].as_ref().unwrap_err().render(),
+                   "error[F0004]: input ended while looking for argument 1 of `\\foo`\n --> STRING CONSTANT:2:5\n  |\n2 | \\foo\n  |     ^\n");
+        assert_matches!(&output[2], Ok(Token::Command(_, _, args))
+            if matches!(args.as_slice(), [Token::ErroneousArgument(location)] if location.line_number == 2 && location.column == 4));
+    }
+
+    #[test]
+    fn errors_have_structured_diagnostics() {
+        let mut parser = Parser::from_string("\\newcommand\\inner{\\foo}\\newcommand\\outer{\\inner}\\outer \\key{a=1, a=2}");
+        parser.define_command("key", vec![(ParameterFormat::Required, ParameterType::KeyValueList)]);
+        parser.define_command_with_handler("foo", vec![],
+            |_: &mut ExecutionContext, location: &Location, _: Vec<Token>| {
+                Err(FinlError::Unimplemented(ErrorContext { location: location.clone(), line_contents: String::new(), expanded_from: Vec::new() }))
+            });
+        let output = parser.parse();
+        let diagnostics: Vec<Diagnostic> = output.iter().filter_map(|item| item.as_ref().err()).map(FinlError::diagnostic).collect();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].code, "F0002");
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[0].primary.location.column, 18);
+        assert_eq!(diagnostics[0].secondary.iter().map(|span| span.location.column).collect::<Vec<_>>(), vec![41, 48]);
+        assert_eq!(diagnostics[1].code, "F0015");
+        assert_eq!(diagnostics[1].severity, Severity::Warning);
+        assert_eq!(diagnostics[1].help, vec!["only the first value is used".to_string()]);
+    }
+
+    #[test]
+    fn undefined_names_get_suggestions() {
+        let mut parser = Parser::from_string("\\textbff{a} \\TEXTIT{b} \\tetxsl{c} \\x \\begin{figur}\\end{figur}");
+        parser.define_from_yaml("commands.yml", include_str!("../resources/commands.yml")).unwrap();
+        parser.define_command("y", vec![]);
+        parser.define_environment("figure", vec![], ParameterType::ParsedTokens);
+        let similar: Vec<Vec<String>> = parser.parse().into_iter()
+            .filter_map(|item| match item {
+                Err(FinlError::UndefinedCommand(_, _, similar)) | Err(FinlError::UndefinedEnvironment(_, _, similar)) => Some(similar),
+                _ => None
+            })
+            .collect();
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        // Case is ignored and a one-letter name is like no other
+        assert_eq!(similar, vec![names(&["textbf"]), names(&["textit"]), names(&["textsl"]), names(&[]), names(&["figure"])]);
+
+        let mut parser = Parser::from_string("\\textbff{a}");
+        parser.define_from_yaml("commands.yml", include_str!("../resources/commands.yml")).unwrap();
+        assert_eq!(parser.parse()[0].as_ref().unwrap_err().diagnostic().help[0], "did you mean `\\textbf`?");
+    }
+
+    #[test]
+    fn parsing_recovers_from_failed_arguments() {
+        // Without a blank line to stop it, the optional argument would take the rest of the input
+        let mut parser = Parser::from_string("\\foo[a\n\nText \\foo{b} \\begin{env}[x\n\ny\\end{env}");
+        parser.define_command("foo", vec![(ParameterFormat::Optional, ParameterType::ParsedTokens),
+                                          (ParameterFormat::Required, ParameterType::ParsedTokens)]);
+        parser.define_environment_with_handler("env", vec![(ParameterFormat::Optional, ParameterType::ParsedTokens)],
+            ParameterType::ParsedTokens, |_: &mut ExecutionContext, _: &Location, _: Vec<Token>, _: Vec<Token>| Ok(Vec::new()));
+        let output = parser.parse();
+        assert_eq!(output.len(), 8);
+        assert_matches!(&output[0], Err(FinlError::BlankLineWhileParsingCommandArguments(context, name, 1))
+            if name == "foo" && context.location.line_number == 2);
+        assert_matches!(&output[1], Ok(Token::Command(_, _, args))
+            if matches!(args.as_slice(), [Token::ErroneousArgument(_), Token::ErroneousArgument(_)]));
+        assert_matches!(&output[2], Ok(Token::ParagraphBreak(_)));
+        assert_matches!(&output[3], Ok(Token::ParsedText(_, text)) if text == "Text ");
+        assert_matches!(&output[4], Ok(Token::Command(_, _, args)) if args.len() == 2);
+        assert_matches!(&output[5], Ok(Token::ParsedText(_, text)) if text == " ");
+        // An environment with a failed argument keeps its body and end, but its handler doesn't run
+        assert_matches!(&output[6], Err(FinlError::BlankLineWhileParsingCommandArguments(_, name, 1)) if name == "env");
+        assert_matches!(&output[7], Ok(Token::Environment(_, _, args, body))
+            if matches!(args.as_slice(), [Token::ErroneousArgument(_)])
+                && matches!(body.as_slice(), [Token::ParagraphBreak(_), Token::ParsedText(_, text)] if text == "y"));
+    }
+
+    #[test]
+    fn unclosed_groups_are_reported_where_they_were_opened() {
+        let mut parser = Parser::from_string("\\begin{env}\n{a \\foo{b {c\nd");
+        parser.define_command("foo", vec![(ParameterFormat::Required, ParameterType::ParsedTokens)]);
+        parser.define_environment("env", vec![], ParameterType::ParsedTokens);
+        let output = parser.parse();
+        let errors: Vec<FinlError> = output.into_iter().filter_map(Result::err).collect();
+        let location = |error: &FinlError| (error.context().location.line_number, error.context().location.column);
+        assert_eq!(errors.len(), 4);
+        // Innermost first
+        assert_matches!(&errors[0], FinlError::UnclosedGroup(_, GroupType::Brace));
+        assert_eq!(location(&errors[0]), (2, 10));
+        assert_matches!(&errors[1], FinlError::UnexpectedEOFWhileParsingCommandArguments(_, name, 1) if name == "foo");
+        assert_eq!(location(&errors[1]), (2, 7));
+        assert_matches!(&errors[2], FinlError::UnclosedGroup(_, GroupType::Brace));
+        assert_eq!(location(&errors[2]), (2, 0));
+        assert_matches!(&errors[3], FinlError::UnclosedGroup(_, GroupType::Environment(environment)) if environment.name == "env");
+        assert_eq!(location(&errors[3]), (1, 0));
+        assert_eq!(errors[3].to_string(), "input ended before the environment `env` was closed");
+    }
+
+    #[test]
+    fn blank_lines_break_paragraphs() {
+        let mut parser = Parser::from_string("a\n\n  \n\nb\n{c\n\nd}\n");
+        let output: Vec<Token> = parser.parse().into_iter().map(Result::unwrap).collect();
+        assert_eq!(output.len(), 8);
+        assert_matches!(&output[0], Token::ParsedText(_, text) if text == "a");
+        // A run of blank lines is a single break, at its first line
+        assert_matches!(&output[1], Token::ParagraphBreak(location) if location.line_number == 2 && location.column == 0);
+        assert_matches!(&output[2], Token::ParsedText(location, text) if text == "b " && location.line_number == 5);
+        assert_matches!(&output[3], Token::Bgroup(_));
+        assert_matches!(&output[4], Token::ParsedText(_, text) if text == "c");
+        assert_matches!(&output[5], Token::ParagraphBreak(location) if location.line_number == 7);
+        assert_matches!(&output[6], Token::ParsedText(_, text) if text == "d");
+        assert_matches!(&output[7], Token::Egroup(_));
+
+        // There's no paragraph to break before any text or right after another break
+        let mut parser = Parser::from_string("  \n  a\n\n%\n\nb");
+        let output: Vec<Token> = parser.parse().into_iter().map(Result::unwrap).collect();
+        assert_eq!(output.len(), 3);
+        assert_matches!(&output[0], Token::ParsedText(_, text) if text == "a");
+        assert_matches!(&output[1], Token::ParagraphBreak(location) if location.line_number == 3);
+        assert_matches!(&output[2], Token::ParsedText(_, text) if text == "b");
+    }
+
+    #[test]
+    fn white_space_is_handled_as_in_tex() {
+        let text = |input: &str| -> String {
+            let mut parser = Parser::from_string(input);
+            parser.define_command("x", vec![]);
+            parser.define_command("\\", vec![]);
+            parser.parse().into_iter().map(|item| item.unwrap().to_string()).collect()
+        };
+        // A line end is a space
+        assert_eq!(text("foo\nbar"), "foo bar");
+        assert_eq!(text("{foo}\n{bar}"), "bgroupfooegroup bgroupbaregroup");
+        // Runs of spaces and tabs collapse, including into a line end, but a no-break space is kept
+        assert_eq!(text("a  \t b \nc"), "a b c");
+        assert_eq!(text("a\u{a0}\u{a0}b"), "a\u{a0}\u{a0}b");
+        // Leading indentation is ignored
+        assert_eq!(text("  foo\n    bar"), "foo bar");
+        // `%` takes the line end with it
+        assert_eq!(text("foo%comment\nbar"), "foobar");
+        assert_eq!(text("foo %\nbar"), "foo bar");
+        // As do the spaces after a control word
+        assert_eq!(text("\\x\nbar"), "\\xbar");
+        assert_eq!(text("\\\\x\nbar"), "\\\\x bar");
+        // There's no space at the end of a paragraph or the start of one
+        assert_eq!(text("foo\n\n\\x{}\nbar"), "foo\n\n\\xbgroupegroup bar");
+        assert_eq!(text("{}\n\n{}\nbar"), "bgroupegroup\n\nbgroupegroup bar");
+        assert_eq!(text("\\newcommand\\y{}\nfoo\n\n\\newcommand\\z{}\nbar"), "foo\n\nbar");
+    }
+}
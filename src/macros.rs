@@ -0,0 +1,397 @@
+use std::rc::Rc;
+
+use crate::{CommandContext, Parser, SkipWhiteSpaceOutcome};
+use crate::commands::{Command, ParameterFormat, ParameterType};
+use crate::font::FontStack;
+use crate::tokens::{ErrorContext, FinlError, GroupType, Location, Token};
+
+pub(crate) const DEFAULT_MAX_EXPANSION_DEPTH: usize = 100;
+pub(crate) const DEFAULT_MAX_EXPANSIONS: usize = 10_000;
+pub(crate) const DEFAULT_MAX_EXPANSION_TOKENS: usize = 100_000;
+
+// The macros being expanded, outermost first, with where each was used
+pub(crate) struct Expansion {
+    pub(crate) chain: Vec<(String, Location)>,
+    pub(crate) count: usize,
+    pub(crate) tokens: usize,
+    pub(crate) aborted: bool,
+    pub(crate) max_depth: usize,
+    pub(crate) max_expansions: usize,
+    pub(crate) max_tokens: usize,
+}
+
+impl Default for Expansion {
+    fn default() -> Self {
+        Expansion {
+            chain: Vec::new(),
+            count: 0,
+            tokens: 0,
+            aborted: false,
+            max_depth: DEFAULT_MAX_EXPANSION_DEPTH,
+            max_expansions: DEFAULT_MAX_EXPANSIONS,
+            max_tokens: DEFAULT_MAX_EXPANSION_TOKENS,
+        }
+    }
+}
+
+impl<'a> Parser<'a> {
+    // `\newcommand{\name}[parameters]{body}` defines a macro. The parameters are a comma-separated list of formats
+    // named as in definition files, each optionally followed by `:type` and, for an optional parameter, `=default`.
+    // Inside the body of another macro, the definition is only made when that macro is expanded, and `#n` in it is
+    // that macro's parameter.
+    pub(crate) fn macro_definition_parse(&mut self, command_context: CommandContext, location: Location) {
+        let nested = matches!(command_context, CommandContext::UserCommandDefinition);
+        match self.macro_definition(nested) {
+            Ok(command) if nested => self.push_token(Token::Definition(location, Rc::new(command))),
+            Ok(command) => {
+                self.commands.insert(command.name.clone(), Rc::new(command));
+            }
+            Err(error) => self.push_error(error),
+        }
+    }
+
+    fn macro_definition(&mut self, nested: bool) -> Result<Command, FinlError> {
+        let (name, name_column) = self.macro_name()?;
+        // We take the whole definition before reporting problems with it, so it doesn't get parsed as text.
+        let parameters = self.macro_parameters();
+        let already_defined = !nested && self.commands.contains_key(&name);
+        let placeholder = !nested && !already_defined && parameters.is_ok();
+        if let (true, Ok(parameters)) = (placeholder, &parameters) {
+            // A macro can use itself. Which definition is used is only decided when it's expanded.
+            self.commands.insert(name.clone(), Rc::new(Command::with_body(&name, parameters.clone(), Vec::new())));
+        }
+        let body = self.parse_required_argument("newcommand", 3, &CommandContext::UserCommandDefinition,
+                                                ParameterType::MacroDefinition, true);
+        if placeholder {
+            self.commands.remove(&name);
+        }
+        if already_defined {
+            return Err(FinlError::CommandAlreadyDefined(ErrorContext::from_line_and_column(&self.line, name_column), name));
+        }
+        let parameters = parameters?;
+        let body = match body? {
+            Token::Tokens(_, body) => body,
+            token => vec![token],
+        };
+        if !nested {
+            if let Some(Token::Parameter(location, number)) = find_parameter(&body, &|number| number == 0 || number > parameters.len()) {
+                return Err(FinlError::ArgumentOutOfRange(self.error_context_at(location), name, *number));
+            }
+        }
+        Ok(Command::with_body(&name, parameters, body))
+    }
+
+    // The name can be given with or without braces
+    fn macro_name(&mut self) -> Result<(String, usize), FinlError> {
+        match self.skip_whitespace() {
+            SkipWhiteSpaceOutcome::Skipped => {}
+            SkipWhiteSpaceOutcome::FoundBlankLine =>
+                return Err(self.blank_line_while_parsing_command_arguments("newcommand".to_string(), 1)),
+            SkipWhiteSpaceOutcome::EndOfFile =>
+                return Err(self.unexpected_eof_while_parsing_command_arguments("newcommand".to_string(), 1)),
+        }
+        let braced = matches!(self.char_iterator.peek(), Some((_, '{')));
+        if braced {
+            self.char_iterator.next();
+            self.skip_spaces();
+        }
+        let column = self.get_column();
+        if !matches!(self.char_iterator.next(), Some((_, '\\'))) {
+            return Err(self.invalid_definition(column, "a command name"));
+        }
+        let name = self.get_command_name(&CommandContext::UserCommandDefinition);
+        if braced {
+            self.skip_spaces();
+            let column = self.get_column();
+            if !matches!(self.char_iterator.next(), Some((_, '}'))) {
+                return Err(self.invalid_definition(column, "`}` after the command name"));
+            }
+        }
+        Ok((name, column))
+    }
+
+    fn macro_parameters(&mut self) -> Result<Vec<(ParameterFormat, ParameterType)>, FinlError> {
+        self.skip_spaces();
+        let open_column = match self.char_iterator.peek() {
+            Some((column, '[')) => *column,
+            _ => return Ok(Vec::new()),
+        };
+        self.char_iterator.next();
+        let start = self.get_column();
+        let mut brace_depth = 0usize;
+        let end = loop {
+            match self.char_iterator.next() {
+                None => return Err(self.unexpected_eol_while_parsing_command_arguments("newcommand".to_string(), 2,
+                                                                                       GroupType::OptionalArgument, open_column)),
+                Some((column, ']')) if brace_depth == 0 => break column,
+                Some((_, '{')) => brace_depth += 1,
+                Some((_, '}')) => brace_depth = brace_depth.saturating_sub(1),
+                _ => {}
+            }
+        };
+        let spec = self.line.contents[start..end].to_string();
+        split_parameter_spec(&spec).into_iter()
+            .filter(|(_, item)| !item.trim().is_empty())
+            .map(|(offset, item)| self.macro_parameter(item, start + offset))
+            .collect()
+    }
+
+    fn macro_parameter(&self, item: &str, column: usize) -> Result<(ParameterFormat, ParameterType), FinlError> {
+        let column = column + item.len() - item.trim_start().len();
+        let (spec, default) = match item.split_once('=') {
+            Some((spec, default)) => (spec, Some(default.trim())),
+            None => (item, None),
+        };
+        let (format_name, type_name) = match spec.split_once(':') {
+            Some((format_name, type_name)) => (format_name.trim(), Some(type_name.trim())),
+            None => (spec.trim(), None),
+        };
+        let error_context = ErrorContext::from_line_and_column(&self.line, column);
+        let mut format = ParameterFormat::from_name(format_name)
+            .ok_or_else(|| FinlError::UnknownParameterFormat(error_context, format_name.to_string()))?;
+        let ptype = match type_name {
+            Some(type_name) => ParameterType::from_name(type_name).ok_or_else(|| {
+                FinlError::UnknownParameterType(ErrorContext::from_line_and_column(&self.line, column), type_name.to_string())
+            })?,
+            None => format.default_type(),
+        };
+        if let Some(default) = default {
+            if format != ParameterFormat::Optional {
+                return Err(self.invalid_definition(column, "a default only for an optional parameter"));
+            }
+            format = ParameterFormat::OptionalWithDefault(default.to_string());
+        }
+        Ok((format, ptype))
+    }
+
+    // `#n` is the nth parameter and `##` is a literal `#`
+    pub(crate) fn macro_parameter_parse(&mut self, column: usize) {
+        let location = Location::from_line_and_column(&self.line, column);
+        self.char_iterator.next();
+        let start = self.get_column();
+        while let Some((_, ch)) = self.char_iterator.peek() {
+            if ch.is_ascii_digit() {
+                self.char_iterator.next();
+            }
+            else {
+                break;
+            }
+        }
+        let end = self.get_column();
+        if start != end {
+            let number = self.line.contents[start..end].parse().unwrap_or(usize::MAX);
+            self.push_token(Token::Parameter(location, number));
+        }
+        else if let Some((_, '#')) = self.char_iterator.peek() {
+            self.char_iterator.next();
+            self.push_token(Token::ParsedText(location, "#".to_string()));
+        }
+        else {
+            self.push_error(self.invalid_definition(start, "a parameter number after `#`"));
+        }
+    }
+
+    // The body's parameters are replaced by the arguments and any commands in it are executed. A macro used
+    // directly in the document starts a new expansion, which may only go so deep, involve so many macros and
+    // substitute so many tokens.
+    pub(crate) fn expand_macro(&mut self, name: &str, body: &[Token], args: &[Token], location: &Location) {
+        if self.begin_expansion(name, location, substituted_size(body, args)) {
+            self.replay(substitute_tokens(body, args));
+            self.expansion.chain.pop();
+        }
+    }
+
+    // Check the limits before expanding `name` into `tokens` tokens, counting those inside others. It's pushed onto
+    // the chain if it may be expanded and the caller pops it when done. Parsing the default of an omitted optional
+    // argument counts as an expansion of its command.
+    pub(crate) fn begin_expansion(&mut self, name: &str, location: &Location, tokens: usize) -> bool {
+        if self.expansion.chain.is_empty() {
+            self.expansion.count = 0;
+            self.expansion.tokens = 0;
+            self.expansion.aborted = false;
+        }
+        if self.expansion.aborted {
+            return false;
+        }
+        self.expansion.count += 1;
+        self.expansion.tokens = self.expansion.tokens.saturating_add(tokens);
+        let too_deep = self.expansion.chain.len() >= self.expansion.max_depth;
+        if too_deep || self.expansion.count > self.expansion.max_expansions || self.expansion.tokens > self.expansion.max_tokens {
+            let mut chain: Vec<String> = self.expansion.chain.iter().map(|(name, _)| name.clone()).collect();
+            chain.push(name.to_string());
+            let error_context = self.error_context_at(location);
+            self.push_error(if too_deep {
+                FinlError::ExpansionTooDeep(error_context, chain)
+            }
+            else {
+                FinlError::TooManyExpansions(error_context, chain)
+            });
+            // Whatever is left of the expansion would fail the same way
+            self.expansion.aborted = true;
+            return false;
+        }
+        self.expansion.chain.push((name.to_string(), location.clone()));
+        true
+    }
+
+    // Execute the commands in tokens from a macro body, including those inside arguments. Commands are looked up
+    // again so that a macro sees its own finished definition, but since the arguments were parsed when the macro was
+    // defined, a definition with other parameters isn't used.
+    fn replay(&mut self, tokens: Vec<Token>) {
+        for token in tokens {
+            match token {
+                Token::Command(location, command, args) => {
+                    let command = self.commands.get(&command.name)
+                        .filter(|current| current.parameters == command.parameters)
+                        .cloned()
+                        .unwrap_or(command);
+                    let args = args.into_iter().map(|arg| self.replay_argument(arg)).collect();
+                    self.push_command(command, args, location);
+                }
+                Token::Environment(location, environment, args, body) => {
+                    let args = args.into_iter().map(|arg| self.replay_argument(arg)).collect();
+                    let body = self.replay_tokens(body);
+                    self.push_environment(environment, args, body, location);
+                }
+                Token::Definition(location, command) => {
+                    if self.commands.contains_key(&command.name) {
+                        self.push_error(FinlError::CommandAlreadyDefined(self.error_context_at(&location), command.name.clone()));
+                    }
+                    else {
+                        self.commands.insert(command.name.clone(), command);
+                    }
+                }
+                Token::Bgroup(location) => {
+                    self.context.get_or_default::<FontStack>().begin_group();
+                    self.push_token(Token::Bgroup(location));
+                }
+                Token::Egroup(location) => {
+                    self.context.get_or_default::<FontStack>().end_group();
+                    self.push_token(Token::Egroup(location));
+                }
+                token => self.push_token(token),
+            }
+        }
+    }
+
+    fn replay_tokens(&mut self, tokens: Vec<Token>) -> Vec<Token> {
+        let start = self.output.len();
+        self.replay(tokens);
+        self.take_tokens_from(start)
+    }
+
+    // As when parsing, a command argument becomes whatever the command stands for and a token list is a scope
+    // for font declarations
+    fn replay_argument(&mut self, arg: Token) -> Token {
+        match arg {
+            Token::Tokens(location, tokens) => {
+                self.context.get_or_default::<FontStack>().begin_group();
+                let tokens = self.replay_tokens(tokens);
+                self.context.get_or_default::<FontStack>().end_group();
+                Token::Tokens(location, tokens)
+            }
+            Token::Command(..) => {
+                let location = arg.location().clone();
+                let mut tokens = self.replay_tokens(vec![arg]);
+                if tokens.len() == 1 {
+                    tokens.remove(0)
+                }
+                else {
+                    Token::Tokens(location, tokens)
+                }
+            }
+            Token::KeyValueList(location, list) => {
+                let list = list.into_iter().map(|(key, value)| (key, self.replay_tokens(value))).collect();
+                Token::KeyValueList(location, list)
+            }
+            arg => arg,
+        }
+    }
+
+    fn invalid_definition(&self, column: usize, expected: &str) -> FinlError {
+        FinlError::InvalidDefinition(ErrorContext::from_line_and_column(&self.line, column), expected.to_string())
+    }
+}
+
+// Split at commas outside of braces, giving each item with its offset
+fn split_parameter_spec(spec: &str) -> Vec<(usize, &str)> {
+    let mut items = Vec::new();
+    let mut brace_depth = 0usize;
+    let mut start = 0;
+    for (index, ch) in spec.char_indices() {
+        match ch {
+            '{' => brace_depth += 1,
+            '}' => brace_depth = brace_depth.saturating_sub(1),
+            ',' if brace_depth == 0 => {
+                items.push((start, &spec[start..index]));
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push((start, &spec[start..]));
+    items
+}
+
+fn find_parameter<'t>(tokens: &'t [Token], test: &dyn Fn(usize) -> bool) -> Option<&'t Token> {
+    tokens.iter().find_map(|token| match token {
+        Token::Parameter(_, number) if test(*number) => Some(token),
+        Token::Command(_, _, args) => find_parameter(args, test),
+        Token::Environment(_, _, args, body) => find_parameter(args, test).or_else(|| find_parameter(body, test)),
+        Token::Tokens(_, tokens) => find_parameter(tokens, test),
+        Token::Definition(_, command) => find_parameter(command.body.as_deref().unwrap_or_default(), test),
+        Token::KeyValueList(_, list) => list.values().find_map(|value| find_parameter(value, test)),
+        _ => None,
+    })
+}
+
+// How many tokens, counting those inside others, substituting `args` into `tokens` gives. This is worked out
+// before substituting, since the result may be too big to build.
+fn substituted_size(tokens: &[Token], args: &[Token]) -> usize {
+    tokens.iter().fold(0usize, |size, token| size.saturating_add(match token {
+        Token::Parameter(_, number) => args.get(number - 1).map_or(1, |arg| substituted_size(std::slice::from_ref(arg), &[])),
+        Token::Command(_, _, tokens) | Token::Tokens(_, tokens) | Token::Font(_, _, tokens) =>
+            substituted_size(tokens, args).saturating_add(1),
+        Token::Environment(_, _, environment_args, body) =>
+            substituted_size(environment_args, args).saturating_add(substituted_size(body, args)).saturating_add(1),
+        Token::Definition(_, command) => substituted_size(command.body.as_deref().unwrap_or_default(), args).saturating_add(1),
+        Token::KeyValueList(_, list) =>
+            list.values().fold(1usize, |size, value| size.saturating_add(substituted_size(value, args))),
+        _ => 1,
+    }))
+}
+
+// An argument in braces is spliced into a token list, but stays whole where it stands for a single argument
+fn substitute_tokens(tokens: &[Token], args: &[Token]) -> Vec<Token> {
+    let mut substituted = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        match token {
+            Token::Parameter(_, number) => match &args[number - 1] {
+                Token::Tokens(_, arg) => substituted.extend(arg.iter().cloned()),
+                arg => substituted.push(arg.clone()),
+            },
+            token => substituted.push(substitute(token, args)),
+        }
+    }
+    substituted
+}
+
+fn substitute(token: &Token, args: &[Token]) -> Token {
+    match token {
+        Token::Parameter(_, number) => args[number - 1].clone(),
+        Token::Command(location, command, command_args) =>
+            Token::Command(location.clone(), command.clone(), command_args.iter().map(|arg| substitute(arg, args)).collect()),
+        Token::Environment(location, environment, environment_args, body) =>
+            Token::Environment(location.clone(), environment.clone(),
+                               environment_args.iter().map(|arg| substitute(arg, args)).collect(),
+                               substitute_tokens(body, args)),
+        Token::Tokens(location, tokens) => Token::Tokens(location.clone(), substitute_tokens(tokens, args)),
+        Token::Definition(location, command) =>
+            Token::Definition(location.clone(), Rc::new(Command::with_body(&command.name, command.parameters.clone(),
+                substitute_tokens(command.body.as_deref().unwrap_or_default(), args)))),
+        Token::KeyValueList(location, list) =>
+            Token::KeyValueList(location.clone(),
+                                list.iter().map(|(key, value)| (key.clone(), substitute_tokens(value, args))).collect()),
+        token => token.clone(),
+    }
+}
@@ -0,0 +1,27 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::context::ExecutionContext;
+use crate::font;
+use crate::implementation::ExpandedArgument;
+use crate::tokens::{ErrorContext, FinlError, Token};
+
+// A method named in a command's implementation. It gets the command's name and the expanded implementation
+// arguments and, as with a handler, whatever it returns replaces the command.
+pub trait InternalMethod {
+    fn execute(&self, context: &mut ExecutionContext, error_context: &ErrorContext, command: &str, args: Vec<ExpandedArgument>) -> Result<Vec<Token>, FinlError>;
+}
+
+impl<F> InternalMethod for F
+    where F: Fn(&mut ExecutionContext, &ErrorContext, &str, Vec<ExpandedArgument>) -> Result<Vec<Token>, FinlError> {
+    fn execute(&self, context: &mut ExecutionContext, error_context: &ErrorContext, command: &str, args: Vec<ExpandedArgument>) -> Result<Vec<Token>, FinlError> {
+        self(context, error_context, command, args)
+    }
+}
+
+// The methods every parser starts out with
+pub fn internal_methods() -> HashMap<String, Rc<dyn InternalMethod>> {
+    let mut methods: HashMap<String, Rc<dyn InternalMethod>> = HashMap::new();
+    methods.insert("internal:font".to_string(), Rc::new(font::font));
+    methods
+}
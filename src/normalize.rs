@@ -0,0 +1,34 @@
+use unicode_normalization::{is_nfd, char::{canonical_combining_class, decompose_canonical}};
+
+use crate::tokens::OriginalLine;
+
+// Returns None if the line is already in NFD. Otherwise, we keep track of where each character of the normalized
+// text came from so that locations can point into the text as written.
+pub(crate) fn nfd(line: &str) -> Option<(String, OriginalLine)> {
+    if is_nfd(line) {
+        return None;
+    }
+    let mut decomposed: Vec<(char, usize)> = Vec::with_capacity(line.len());
+    for (offset, ch) in line.char_indices() {
+        decompose_canonical(ch, |piece| decomposed.push((piece, offset)));
+    }
+    // Canonical ordering: each run of combining marks is sorted by combining class, keeping marks of the same
+    // class in order.
+    let mut run_start = 0;
+    for index in 0..=decomposed.len() {
+        if index == decomposed.len() || canonical_combining_class(decomposed[index].0) == 0 {
+            decomposed[run_start..index].sort_by_key(|(ch, _)| canonical_combining_class(*ch));
+            run_start = index + 1;
+        }
+    }
+    let mut contents = String::with_capacity(line.len());
+    let mut offsets = Vec::with_capacity(decomposed.len());
+    for (ch, original_offset) in decomposed {
+        offsets.push((contents.len(), original_offset));
+        contents.push(ch);
+    }
+    Some((contents, OriginalLine {
+        contents: line.to_string(),
+        offsets
+    }))
+}
@@ -0,0 +1,54 @@
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+const MAX_SUGGESTIONS: usize = 3;
+
+// The defined names closest to `name`, best first. Names are compared by grapheme after normalization and case
+// folding, so `\TEXTBF` finds `\textbf` and a missing accent costs one edit however it was typed.
+pub(crate) fn similar_names<'n>(name: &str, candidates: impl Iterator<Item=&'n String>) -> Vec<String> {
+    let folded = fold(name);
+    // Short names are similar to too many things to allow much leeway. A one-grapheme name is only similar to
+    // itself written in another case.
+    let max_distance = (folded.len() / 4).max(1).min(folded.len().saturating_sub(1));
+    let mut similar: Vec<(usize, usize, &String)> = candidates
+        .filter(|candidate| candidate.as_str() != name)
+        .filter_map(|candidate| {
+            let distance = edit_distance(&folded, &fold(candidate));
+            if distance > max_distance {
+                None
+            }
+            else {
+                // Among equally close names, prefer those that match the case as written
+                let exact_distance = edit_distance(&graphemes(name), &graphemes(candidate));
+                Some((distance, exact_distance, candidate))
+            }
+        })
+        .collect();
+    similar.sort();
+    similar.into_iter().take(MAX_SUGGESTIONS).map(|(_, _, candidate)| candidate.clone()).collect()
+}
+
+fn graphemes(text: &str) -> Vec<String> {
+    text.nfd().collect::<String>().graphemes(true).map(str::to_string).collect()
+}
+
+fn fold(text: &str) -> Vec<String> {
+    graphemes(&text.to_lowercase())
+}
+
+// Optimal string alignment distance: insertions, deletions, substitutions and swaps of neighbors each count as one
+fn edit_distance(a: &[String], b: &[String]) -> usize {
+    let mut rows = vec![(0..=b.len()).collect::<Vec<_>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            row[j] = (rows[i - 1][j] + 1).min(row[j - 1] + 1).min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+        rows.push(row);
+    }
+    rows[a.len()][b.len()]
+}
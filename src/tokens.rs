@@ -1,12 +1,55 @@
 use std::fmt::{Display, Formatter};
 use std::rc::Rc;
+use linked_hash_map::LinkedHashMap;
+use yaml_rust2::{Yaml, YamlEmitter};
 use crate::commands::{Command, Environment};
+use crate::font::Font;
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct Line {
     pub file: String,
     pub line_number: usize,
     pub contents: String,
+    // Set when `contents` is a normalized version of what was written
+    pub original: Option<OriginalLine>,
+}
+
+impl Line {
+    // Columns are always given for the text as it was written
+    pub fn original_column(&self, column: usize) -> usize {
+        match &self.original {
+            None => column,
+            Some(original) => match original.offsets.binary_search_by_key(&column, |(offset, _)| *offset) {
+                Ok(index) => original.offsets[index].1,
+                // Past the last character
+                Err(_) => original.contents.len(),
+            }
+        }
+    }
+
+    // The column in `contents` of the character at `column` in the text as it was written
+    pub fn normalized_column(&self, column: usize) -> usize {
+        match &self.original {
+            None => column,
+            Some(original) => original.offsets.iter()
+                .find(|(_, original_column)| *original_column == column)
+                .map_or(self.contents.len(), |(offset, _)| *offset),
+        }
+    }
+
+    pub fn original_contents(&self) -> &str {
+        match &self.original {
+            None => &self.contents,
+            Some(original) => &original.contents,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct OriginalLine {
+    pub contents: String,
+    // Pairs of the byte offset of each character in the normalized line and that of the character it came from
+    pub offsets: Vec<(usize, usize)>,
 }
 
 
@@ -26,7 +69,7 @@ impl Location {
         Location {
             file: line.file.to_string(),
             line_number: line.line_number,
-            column,
+            column: line.original_column(column),
         }
     }
 }
@@ -40,49 +83,272 @@ pub enum GroupType {
     ArbitraryDelim(String), // must be string so we can write, e.g., \verb🇨🇦something🇨🇦
 }
 
-#[derive(Debug, PartialEq, Default)]
+#[derive(Clone, Debug, PartialEq, Default)]
 pub struct ErrorContext {
     pub location: Location,
     pub line_contents: String,
+    // Where each macro that led to the error was used, innermost first. Empty unless the error came up while
+    // expanding a macro, in which case `location` is in the macro's definition.
+    pub expanded_from: Vec<Location>,
 }
 
 impl ErrorContext {
     pub fn from_line_and_column(line: &Line, column: usize) -> ErrorContext {
         ErrorContext {
             location: Location::from_line_and_column(line, column),
-            line_contents: line.contents.clone()
+            line_contents: line.original_contents().to_string(),
+            expanded_from: Vec::new()
         }
     }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum FinlError {
-    UndefinedCommand(ErrorContext, String),
+    UndefinedCommand(ErrorContext, String, Vec<String>), // .2 are similar names which are defined
     Unimplemented(ErrorContext),
     BlankLineWhileParsingCommandArguments(ErrorContext, String, usize), // .2 is the argument number
     UnexpectedEOFWhileParsingCommandArguments(ErrorContext, String, usize),
+    CloseBraceWhileParsingCommandArguments(ErrorContext, String, usize),
+    MissingBracesForCommandArgument(ErrorContext, String, usize),
+    UnexpectedEOLWhileParsingCommandArguments(ErrorContext, String, usize, GroupType), // .3 is the unclosed group
     UnexpectedCloseBrace(ErrorContext, Option<GroupType>),
+    UndefinedEnvironment(ErrorContext, String, Vec<String>),
+    MismatchedEnvironmentEnd(ErrorContext, String, String), // .1 is the open environment, .2 the one being closed
+    UnexpectedEnvironmentEnd(ErrorContext, String),
+    InsufficientIndentationInEnvironment(ErrorContext, String),
+    InvalidYAML(ErrorContext, String),
+    InvalidKey(ErrorContext, String),
+    DuplicateKey(ErrorContext, String),
+    MissingKeyValueSeparator(ErrorContext, String),
+    UnknownParameterFormat(ErrorContext, String),
+    UnknownParameterType(ErrorContext, String),
+    InvalidDefinition(ErrorContext, String), // .1 says what was expected
+    UndefinedVariable(ErrorContext, String, String), // .1 is the command, .2 the variable
+    ArgumentOutOfRange(ErrorContext, String, usize),
+    UndefinedMethod(ErrorContext, String, String), // .1 is the command, .2 the method
+    InvalidMethodArguments(ErrorContext, String, String), // .1 is the method, .2 says what was expected
+    CommandAlreadyDefined(ErrorContext, String),
+    ExpansionTooDeep(ErrorContext, Vec<String>), // .1 is the chain of macros, outermost first
+    TooManyExpansions(ErrorContext, Vec<String>),
+    UnclosedGroup(ErrorContext, GroupType), // at the place the group was opened
+    EnvironmentEndWhileParsingCommandArguments(ErrorContext, String, usize),
+    MissingArgumentDelimiter(ErrorContext, String, usize),
+}
+
+impl FinlError {
+    pub fn context(&self) -> &ErrorContext {
+        match self {
+            FinlError::UndefinedCommand(context, ..) => context,
+            FinlError::Unimplemented(context, ..) => context,
+            FinlError::BlankLineWhileParsingCommandArguments(context, ..) => context,
+            FinlError::UnexpectedEOFWhileParsingCommandArguments(context, ..) => context,
+            FinlError::CloseBraceWhileParsingCommandArguments(context, ..) => context,
+            FinlError::MissingBracesForCommandArgument(context, ..) => context,
+            FinlError::UnexpectedEOLWhileParsingCommandArguments(context, ..) => context,
+            FinlError::UnexpectedCloseBrace(context, ..) => context,
+            FinlError::UndefinedEnvironment(context, ..) => context,
+            FinlError::MismatchedEnvironmentEnd(context, ..) => context,
+            FinlError::UnexpectedEnvironmentEnd(context, ..) => context,
+            FinlError::InsufficientIndentationInEnvironment(context, ..) => context,
+            FinlError::InvalidYAML(context, ..) => context,
+            FinlError::InvalidKey(context, ..) => context,
+            FinlError::DuplicateKey(context, ..) => context,
+            FinlError::MissingKeyValueSeparator(context, ..) => context,
+            FinlError::UnknownParameterFormat(context, ..) => context,
+            FinlError::UnknownParameterType(context, ..) => context,
+            FinlError::InvalidDefinition(context, ..) => context,
+            FinlError::UndefinedVariable(context, ..) => context,
+            FinlError::ArgumentOutOfRange(context, ..) => context,
+            FinlError::UndefinedMethod(context, ..) => context,
+            FinlError::InvalidMethodArguments(context, ..) => context,
+            FinlError::CommandAlreadyDefined(context, ..) => context,
+            FinlError::ExpansionTooDeep(context, ..) => context,
+            FinlError::TooManyExpansions(context, ..) => context,
+            FinlError::UnclosedGroup(context, ..) => context,
+            FinlError::EnvironmentEndWhileParsingCommandArguments(context, ..) => context,
+            FinlError::MissingArgumentDelimiter(context, ..) => context,
+        }
+    }
+
+    pub fn context_mut(&mut self) -> &mut ErrorContext {
+        match self {
+            FinlError::UndefinedCommand(context, ..) => context,
+            FinlError::Unimplemented(context, ..) => context,
+            FinlError::BlankLineWhileParsingCommandArguments(context, ..) => context,
+            FinlError::UnexpectedEOFWhileParsingCommandArguments(context, ..) => context,
+            FinlError::CloseBraceWhileParsingCommandArguments(context, ..) => context,
+            FinlError::MissingBracesForCommandArgument(context, ..) => context,
+            FinlError::UnexpectedEOLWhileParsingCommandArguments(context, ..) => context,
+            FinlError::UnexpectedCloseBrace(context, ..) => context,
+            FinlError::UndefinedEnvironment(context, ..) => context,
+            FinlError::MismatchedEnvironmentEnd(context, ..) => context,
+            FinlError::UnexpectedEnvironmentEnd(context, ..) => context,
+            FinlError::InsufficientIndentationInEnvironment(context, ..) => context,
+            FinlError::InvalidYAML(context, ..) => context,
+            FinlError::InvalidKey(context, ..) => context,
+            FinlError::DuplicateKey(context, ..) => context,
+            FinlError::MissingKeyValueSeparator(context, ..) => context,
+            FinlError::UnknownParameterFormat(context, ..) => context,
+            FinlError::UnknownParameterType(context, ..) => context,
+            FinlError::InvalidDefinition(context, ..) => context,
+            FinlError::UndefinedVariable(context, ..) => context,
+            FinlError::ArgumentOutOfRange(context, ..) => context,
+            FinlError::UndefinedMethod(context, ..) => context,
+            FinlError::InvalidMethodArguments(context, ..) => context,
+            FinlError::CommandAlreadyDefined(context, ..) => context,
+            FinlError::ExpansionTooDeep(context, ..) => context,
+            FinlError::TooManyExpansions(context, ..) => context,
+            FinlError::UnclosedGroup(context, ..) => context,
+            FinlError::EnvironmentEndWhileParsingCommandArguments(context, ..) => context,
+            FinlError::MissingArgumentDelimiter(context, ..) => context,
+        }
+    }
 }
 
 impl Display for FinlError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        // todo
-        write!(f, "todo")
+        match self {
+            FinlError::UndefinedCommand(_, name, _) => write!(f, "undefined command `\\{}`", name),
+            FinlError::Unimplemented(_) => write!(f, "this is not implemented yet"),
+            FinlError::BlankLineWhileParsingCommandArguments(_, command, number) =>
+                write!(f, "blank line while looking for argument {} of `\\{}`", number, command),
+            FinlError::UnexpectedEOFWhileParsingCommandArguments(_, command, number) =>
+                write!(f, "input ended while looking for argument {} of `\\{}`", number, command),
+            FinlError::CloseBraceWhileParsingCommandArguments(_, command, number) =>
+                write!(f, "found `}}` while looking for argument {} of `\\{}`", number, command),
+            FinlError::MissingBracesForCommandArgument(_, command, number) =>
+                write!(f, "argument {} of `\\{}` must be in braces", number, command),
+            FinlError::UnexpectedEOLWhileParsingCommandArguments(_, command, number, group) =>
+                write!(f, "line ended in argument {} of `\\{}` before {} was closed", number, command, group),
+            FinlError::UnexpectedCloseBrace(_, None) => write!(f, "unexpected `}}`"),
+            FinlError::UnexpectedCloseBrace(_, Some(group)) => write!(f, "unexpected `}}` in {}", group),
+            FinlError::UndefinedEnvironment(_, name, _) => write!(f, "undefined environment `{}`", name),
+            FinlError::MismatchedEnvironmentEnd(_, open, close) =>
+                write!(f, "`\\end{{{}}}` does not match `\\begin{{{}}}`", close, open),
+            FinlError::UnexpectedEnvironmentEnd(_, name) =>
+                write!(f, "`\\end{{{}}}` without a matching `\\begin{{{}}}`", name, name),
+            FinlError::InsufficientIndentationInEnvironment(_, name) =>
+                write!(f, "line is indented less than the `\\end{{{}}}` of its environment", name),
+            FinlError::InvalidYAML(_, message) => write!(f, "invalid YAML: {}", message),
+            FinlError::InvalidKey(_, key) => write!(f, "`{}` is not a valid key", key),
+            FinlError::DuplicateKey(_, key) => write!(f, "duplicate key `{}`", key),
+            FinlError::MissingKeyValueSeparator(_, key) => write!(f, "missing `=` after the key `{}`", key),
+            FinlError::UnknownParameterFormat(_, name) => write!(f, "unknown parameter format `{}`", name),
+            FinlError::UnknownParameterType(_, name) => write!(f, "unknown parameter type `{}`", name),
+            FinlError::InvalidDefinition(_, expected) => write!(f, "invalid definition: expected {}", expected),
+            FinlError::UndefinedVariable(_, command, variable) =>
+                write!(f, "undefined variable `{}` in the implementation of `\\{}`", variable, command),
+            FinlError::ArgumentOutOfRange(_, command, number) => write!(f, "`\\{}` has no argument {}", command, number),
+            FinlError::UndefinedMethod(_, command, method) =>
+                write!(f, "undefined method `{}` in the implementation of `\\{}`", method, command),
+            FinlError::InvalidMethodArguments(_, method, expected) =>
+                write!(f, "invalid arguments for `{}`: expected {}", method, expected),
+            FinlError::CommandAlreadyDefined(_, name) => write!(f, "`\\{}` is already defined", name),
+            FinlError::ExpansionTooDeep(_, chain) =>
+                write!(f, "macros nested more than {} deep: {}", chain.len() - 1, MacroChain(chain)),
+            FinlError::TooManyExpansions(_, chain) => write!(f, "too many macro expansions in {}", MacroChain(chain)),
+            FinlError::UnclosedGroup(_, group) => write!(f, "input ended before {} was closed", group),
+            FinlError::EnvironmentEndWhileParsingCommandArguments(_, command, number) =>
+                write!(f, "found `\\end` while looking for argument {} of `\\{}`", number, command),
+            FinlError::MissingArgumentDelimiter(_, command, number) =>
+                write!(f, "line ended before the delimiter of argument {} of `\\{}`", number, command),
+        }
     }
 }
 
-#[derive(Debug, PartialEq)]
+// Long chains only show their ends
+struct MacroChain<'c>(&'c [String]);
+
+impl<'c> Display for MacroChain<'c> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<String> = if self.0.len() > 6 {
+            self.0[..3].iter().map(|name| format!("\\{}", name))
+                .chain(std::iter::once("…".to_string()))
+                .chain(self.0[self.0.len() - 2..].iter().map(|name| format!("\\{}", name)))
+                .collect()
+        }
+        else {
+            self.0.iter().map(|name| format!("\\{}", name)).collect()
+        };
+        write!(f, "{}", names.join(" → "))
+    }
+}
+
+impl Display for GroupType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GroupType::Brace => write!(f, "a brace group"),
+            GroupType::Environment(environment) => write!(f, "the environment `{}`", environment.name),
+            GroupType::RequiredArgument => write!(f, "a required argument"),
+            GroupType::OptionalArgument => write!(f, "an optional argument"),
+            GroupType::ArbitraryDelim(delimiter) => write!(f, "the argument delimited by `{}`", delimiter),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Token {
     ParsedText(Location, String),
     Math(Location, String),
     Command(Location, Rc<Command>, Vec<Token>),
     Environment(Location, Rc<Environment>, Vec<Token>, Vec<Token>),
     RawText(Location, String),
+    Boolean(Location, bool),
+    YAML(Location, Yaml),
+    KeyValueList(Location, LinkedHashMap<String, Vec<Token>>),
+    Font(Location, Font, Vec<Token>),
+    Parameter(Location, usize), // `#n` in a macro definition
+    Definition(Location, Rc<Command>), // `\newcommand` in a macro definition, made when the macro is expanded
+    ParagraphBreak(Location), // one or more blank lines
+    ErroneousArgument(Location), // stands in for an argument which failed to parse
     Bgroup(Location),
     Egroup(Location),
     Tokens(Location, Vec<Token>) // Q: Does this make sense? Yes, for arguments to commands.
 }
 
+impl Token {
+    pub fn location(&self) -> &Location {
+        match self {
+            Token::ParsedText(location, _) => location,
+            Token::Math(location, _) => location,
+            Token::Command(location, _, _) => location,
+            Token::Environment(location, _, _, _) => location,
+            Token::RawText(location, _) => location,
+            Token::Boolean(location, _) => location,
+            Token::YAML(location, _) => location,
+            Token::KeyValueList(location, _) => location,
+            Token::Font(location, _, _) => location,
+            Token::Parameter(location, _) => location,
+            Token::Definition(location, _) => location,
+            Token::ParagraphBreak(location) => location,
+            Token::ErroneousArgument(location) => location,
+            Token::Bgroup(location) => location,
+            Token::Egroup(location) => location,
+            Token::Tokens(location, _) => location,
+        }
+    }
+
+    // Give the token and everything inside it `location`, for tokens from text that isn't in the document
+    pub(crate) fn relocate(&mut self, location: &Location) {
+        match self {
+            Token::Command(_, _, args) | Token::Tokens(_, args) | Token::Font(_, _, args) =>
+                args.iter_mut().for_each(|arg| arg.relocate(location)),
+            Token::Environment(_, _, args, body) =>
+                args.iter_mut().chain(body.iter_mut()).for_each(|token| token.relocate(location)),
+            Token::KeyValueList(_, list) =>
+                list.iter_mut().flat_map(|(_, value)| value.iter_mut()).for_each(|token| token.relocate(location)),
+            _ => {}
+        }
+        match self {
+            Token::ParsedText(own, _) | Token::Math(own, _) | Token::Command(own, _, _)
+            | Token::Environment(own, _, _, _) | Token::RawText(own, _) | Token::Boolean(own, _)
+            | Token::YAML(own, _) | Token::KeyValueList(own, _) | Token::Font(own, _, _)
+            | Token::Parameter(own, _) | Token::Definition(own, _) | Token::ParagraphBreak(own) | Token::ErroneousArgument(own)
+            | Token::Bgroup(own) | Token::Egroup(own) | Token::Tokens(own, _) => *own = location.clone(),
+        }
+    }
+}
+
 impl Display for Token {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -100,6 +366,30 @@ impl Display for Token {
                 }
                 write!(f, "]]")
             },
+            Token::Boolean(_, value) => write!(f, "{}", value),
+            Token::YAML(_, yaml) => YamlEmitter::new(f).dump(yaml).map_err(|_| std::fmt::Error),
+            Token::KeyValueList(_, list) => {
+                for (index, (key, value)) in list.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}=", key)?;
+                    for token in value {
+                        write!(f, "{}", token)?;
+                    }
+                }
+                Ok(())
+            },
+            Token::Font(_, _, tokens) => {
+                for token in tokens {
+                    write!(f, "{}", token)?;
+                }
+                Ok(())
+            },
+            Token::Parameter(_, number) => write!(f, "#{}", number),
+            Token::Definition(_, command) => write!(f, "\\newcommand\\{}", command.name),
+            Token::ParagraphBreak(_) => write!(f, "\n\n"),
+            Token::ErroneousArgument(_) => Ok(()),
             Token::Bgroup(_) => write!(f, "bgroup"),
             Token::Egroup(_) => write!(f, "egroup"),
         }
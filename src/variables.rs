@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+
+// Variables are addressed with dotted paths like `font.series.bold`. Each segment but the last names a table.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VariableStore {
+    variables: HashMap<String, Variable>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Variable {
+    Value(String),
+    Table(VariableStore),
+}
+
+impl VariableStore {
+    pub fn get(&self, path: &str) -> Option<&Variable> {
+        let mut segments = path.split('.');
+        let mut variable = self.variables.get(segments.next()?)?;
+        for segment in segments {
+            match variable {
+                Variable::Table(table) => variable = table.variables.get(segment)?,
+                Variable::Value(_) => return None,
+            }
+        }
+        Some(variable)
+    }
+
+    pub fn get_value(&self, path: &str) -> Option<&str> {
+        match self.get(path)? {
+            Variable::Value(value) => Some(value),
+            Variable::Table(_) => None,
+        }
+    }
+
+    // Tables along the path are created as needed, replacing any values in the way.
+    pub fn set(&mut self, path: &str, value: &str) {
+        match path.split_once('.') {
+            None => {
+                self.variables.insert(path.to_string(), Variable::Value(value.to_string()));
+            }
+            Some((first, rest)) => {
+                let variable = self.variables.entry(first.to_string())
+                    .or_insert_with(|| Variable::Table(VariableStore::default()));
+                if let Variable::Value(_) = variable {
+                    *variable = Variable::Table(VariableStore::default());
+                }
+                if let Variable::Table(table) = variable {
+                    table.set(rest, value);
+                }
+            }
+        }
+    }
+}